@@ -0,0 +1,13 @@
+// Compiles the C lexer ahead of time, for `clex::clex` to load
+#[allow(dead_code)]
+#[path = "src/clex"]
+mod clex {
+    pub mod rules;
+    mod token;
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/clex");
+    let lexer = clex::rules::compile().unwrap_or_else(|e| panic!("{}", e));
+    lexer.write_to_out_dir("clex.bin").expect("write clex.bin");
+}
@@ -1,155 +1,76 @@
-// https://www.lysator.liu.se/c/ANSI-C-grammar-l.html
-
 use anyhow::anyhow;
-use lexer::{combinator::Parser, Action, Lexer};
+use lexer::{LexError, Lexeme, Lexer, LineIndex};
 
+mod rules;
 mod token;
-use token::{
-    IntegerToken,
-    KeywordToken::*,
-    LiteralToken::*,
-    OperatorToken::{self, *},
-    Token::{self, *},
-};
-
-pub type ActionT = Action<Option<Token>, anyhow::Error>;
-pub type LexerT = Lexer<Option<Token>, anyhow::Error>;
-
-fn constant(x: Token) -> ActionT {
-    Action::C(Some(x))
-}
-
-macro_rules! keyword {
-    ($x:ident) => {
-        (casey::lower!(stringify!($x)), constant(Keyword($x)))
-    };
-}
+pub use rules::LexerT;
+use token::Located;
 
-fn op(x: OperatorToken) -> ActionT {
-    constant(Operator(x))
-}
-
-fn identifier(s: &[u8]) -> anyhow::Result<Option<Token>> {
-    let t = std::str::from_utf8(s)?;
-    Ok(Some(Identifier(t.to_owned())))
-}
+// The rules, compiled by the build script
+static CLEX: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/clex.bin"));
 
-fn integer_literal(s: &[u8]) -> anyhow::Result<Option<Token>> {
-    use IntegerToken::*;
-    let i = s.partition_point(|x| (b'0'..=b'9').contains(x));
-    let (l, r) = s.split_at(i);
-    let t = std::str::from_utf8(l)?;
-    let k: i64 = t.parse()?;
-    let x = match r {
-        b"" | b"l" => Ok(L(k as i32)),
-        b"ll" => Ok(LL(k as i64)),
-        b"u" | b"ul" | b"lu" => Ok(UL(k as u32)),
-        b"ull" | b"llu" => Ok(ULL(k as u64)),
-        _ => Err(anyhow!("integer_literal {}", t)),
-    };
-    Ok(Some(Literal(LInt(x?))))
+// The lexer of `rules::compile`, loaded from what the build script wrote
+pub fn clex() -> anyhow::Result<LexerT> {
+    clex_from_bytes(CLEX)
 }
 
-pub fn clex() -> anyhow::Result<LexerT> {
-    let v: Vec<(&str, ActionT)> = vec![
-        ("//(.)*\n|/\\*(.|\n)*\\*/|(\\s)+", Action::C(None)),
-        keyword!(Auto),
-        keyword!(Break),
-        keyword!(Case),
-        keyword!(Char),
-        keyword!(Const),
-        keyword!(Continue),
-        keyword!(Default),
-        keyword!(Do),
-        keyword!(Double),
-        keyword!(Else),
-        keyword!(Enum),
-        keyword!(Extern),
-        keyword!(Float),
-        keyword!(For),
-        keyword!(Goto),
-        keyword!(If),
-        keyword!(Int),
-        keyword!(Long),
-        keyword!(Register),
-        keyword!(Return),
-        keyword!(Short),
-        keyword!(Signed),
-        keyword!(Sizeof),
-        keyword!(Static),
-        keyword!(Struct),
-        keyword!(Switch),
-        keyword!(Typedef),
-        keyword!(Union),
-        keyword!(Unsigned),
-        keyword!(Void),
-        keyword!(Volatile),
-        keyword!(While),
-        (r"\.\.\.", op(Ellipsis)),
-        (r">>=", op(ShrAsn)),
-        (r"<<=", op(ShlAsn)),
-        (r"\+=", op(AddAsn)),
-        (r"\-=", op(SubAsn)),
-        (r"\*=", op(MulAsn)),
-        (r"/=", op(DivAsn)),
-        (r"%=", op(ModAsn)),
-        (r"&=", op(AndAsn)),
-        (r"\^=", op(XorAsn)),
-        (r"\|=", op(OrAsn)),
-        (r">>", op(Shr)),
-        (r"<<", op(Shl)),
-        (r"\+\+", op(Inc)),
-        (r"\-\-", op(Dec)),
-        (r"\->", op(Ptr)),
-        (r"&&", op(And)),
-        (r"\|\|", op(Or)),
-        (r"<=", op(Le)),
-        (r">=", op(Ge)),
-        (r"==", op(Eq)),
-        (r"!=", op(Ne)),
-        (r";", op(Semicolon)),
-        (r"\{|<%", op(LBrace)),
-        (r"\}|>%", op(RBrace)),
-        (r",", op(Comma)),
-        (r":", op(Colon)),
-        (r"=", op(Assign)),
-        (r"\(", op(LParen)),
-        (r"\)", op(RParen)),
-        (r"\[|<:", op(LSqBr)),
-        (r"\]|>:", op(RSqBr)),
-        (r"\.", op(Dot)),
-        (r"&", op(BitAnd)),
-        (r"!", op(Not)),
-        (r"~", op(Tilde)),
-        (r"\-", op(Minus)),
-        (r"\+", op(Plus)),
-        (r"\*", op(Ast)),
-        (r"/", op(Div)),
-        (r"%", op(Mod)),
-        (r"<", op(Lt)),
-        (r">", op(Gt)),
-        (r"\^", op(Caret)),
-        (r"\|", op(BitOr)),
-        (r"\?", op(QnMk)),
-        (r"[a-zA-Z_](\w)*", Action::F(identifier)),
-        (r"(\-)?(\d)+([uUlL])*", Action::F(integer_literal)),
-    ];
-    Lexer::new(v.into_iter()).map_err(|s| anyhow!("clex:ParseRegexError {}", s.0))
+// Loads what `rules::compile()?.to_bytes()` wrote, skipping compilation
+pub fn clex_from_bytes(bytes: &[u8]) -> anyhow::Result<LexerT> {
+    let actions = rules::rules().into_iter().map(|(_, x)| x);
+    Lexer::from_bytes(bytes, actions).map_err(|e| anyhow!("clex: {}", e))
 }
 
-pub fn tokens(lexer: LexerT, code: &str) -> anyhow::Result<Vec<Token>> {
-    Ok(lexer
-        .items(code.as_bytes())
-        .collect::<anyhow::Result<Vec<Option<Token>>>>()?
-        .into_iter()
-        .filter_map(|x| x)
-        .collect())
+// Lexes all of `code`, failing with every error found, one per line,
+// each prefixed with the line and column of its lexeme
+pub fn tokens<'a>(
+    lexer: &LexerT,
+    code: &'a str,
+) -> anyhow::Result<Vec<Located<'a>>> {
+    let index = LineIndex::new(code.as_bytes());
+    let mut v = Vec::new();
+    let mut errors = Vec::new();
+    for (x, lexeme) in lexer.tokens(code.as_bytes()).lexemes() {
+        let Lexeme { text, span } = lexeme;
+        let position = index.position(span.start);
+        match x {
+            Ok(Some(token)) => v.push(Located {
+                token,
+                span,
+                position,
+            }),
+            Ok(None) => {}
+            Err(LexError::Unmatched) => {
+                let s = String::from_utf8_lossy(text);
+                errors.push(format!("{}: unexpected `{}`", position, s))
+            }
+            Err(e) => errors.push(format!("{}: {}", position, e)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(v)
+    } else {
+        Err(anyhow!("{}", errors.join("\n")))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::clex::*;
-    use lexer::combinator::Parser;
+    use lexer::Position;
+    use token::{
+        FloatToken, IntegerToken,
+        KeywordToken::*,
+        LiteralToken::*,
+        OperatorToken::*,
+        Token::{self, *},
+    };
+
+    fn bare<'a>(
+        lexer: &LexerT,
+        code: &'a str,
+    ) -> anyhow::Result<Vec<Token<'a>>> {
+        Ok(tokens(lexer, code)?.into_iter().map(|x| x.token).collect())
+    }
 
     #[test]
     fn clex_test() -> anyhow::Result<()> {
@@ -159,12 +80,12 @@ mod tests {
                 return 0;
             }
         "#;
-        let tokens = tokens(lexer, code)?;
+        let tokens = bare(&lexer, code)?;
         assert_eq!(
             tokens,
             vec![
                 Keyword(Int),
-                Identifier("main".to_string()),
+                Identifier("main"),
                 Operator(LParen),
                 Operator(RParen),
                 Operator(LBrace),
@@ -176,4 +97,116 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn clex_unicode_identifier() -> anyhow::Result<()> {
+        let lexer = clex()?;
+        let tokens = bare(&lexer, "int café = 1; /* ünïcödé */")?;
+        assert_eq!(
+            tokens,
+            vec![
+                Keyword(Int),
+                Identifier("café"),
+                Operator(Assign),
+                Literal(LInt(IntegerToken::L(1))),
+                Operator(Semicolon),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn clex_block_comments() -> anyhow::Result<()> {
+        let lexer = clex()?;
+        let tokens = bare(&lexer, "/* a */ x = ~y & z; /* b */")?;
+        assert_eq!(
+            tokens,
+            vec![
+                Identifier("x"),
+                Operator(Assign),
+                Operator(Tilde),
+                Identifier("y"),
+                Operator(BitAnd),
+                Identifier("z"),
+                Operator(Semicolon),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn clex_constants() -> anyhow::Result<()> {
+        let lexer = clex()?;
+        let tokens =
+            bare(&lexer, r"1.5e3f .25 1. 2e-1L 42ul -7 '\n' '\x41' 'a' 'é'")?;
+        assert_eq!(
+            tokens,
+            vec![
+                Literal(LFloat(FloatToken::F(1.5e3))),
+                Literal(LFloat(FloatToken::D(0.25))),
+                Literal(LFloat(FloatToken::D(1.0))),
+                Literal(LFloat(FloatToken::L(0.2))),
+                Literal(LInt(IntegerToken::UL(42))),
+                Literal(LInt(IntegerToken::L(-7))),
+                Literal(LChar(0x0A)),
+                Literal(LChar(0x41)),
+                Literal(LChar(0x61)),
+                Literal(LChar(0xE9)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn clex_bytes() -> anyhow::Result<()> {
+        let code = "int x = 1.5e3f; /* c */ char c = '\\n';";
+        let lexer = clex()?;
+        let bytes = lexer.to_bytes();
+        let expected = tokens(&lexer, code)?;
+        assert_eq!(tokens(&clex_from_bytes(&bytes)?, code)?, expected);
+        assert!(clex_from_bytes(&bytes[1..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn clex_prebuilt() -> anyhow::Result<()> {
+        // The build script wrote the lexer the rules compile to now
+        assert_eq!(clex()?.to_bytes(), rules::compile()?.to_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn clex_locations() -> anyhow::Result<()> {
+        let code = "int x;\n  /* é */ y = 'é';";
+        let located = tokens(&clex()?, code)?;
+        let at = |line, column| Position { line, column };
+        let v: Vec<_> = located
+            .iter()
+            .map(|x| (x.span.clone(), x.position))
+            .collect();
+        assert_eq!(
+            v,
+            vec![
+                (0..3, at(1, 1)),
+                (4..5, at(1, 5)),
+                (5..6, at(1, 6)),
+                (18..19, at(2, 11)),
+                (20..21, at(2, 13)),
+                (22..26, at(2, 15)),
+                (26..27, at(2, 18)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn clex_errors() -> anyhow::Result<()> {
+        let e = tokens(&clex()?, "int @x = 1;\n$ y = 2; 'ab'").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "1:5: unexpected `@`\n2:1: unexpected `$`\n\
+             2:10: unexpected `'`\n2:13: unexpected `'`"
+        );
+        Ok(())
+    }
 }
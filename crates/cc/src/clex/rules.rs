@@ -0,0 +1,213 @@
+// https://www.lysator.liu.se/c/ANSI-C-grammar-l.html
+
+use anyhow::anyhow;
+use lexer::{Action, Captures, Lexer, LexerBuilder};
+
+use super::token::{
+    FloatToken, IntegerToken,
+    KeywordToken::*,
+    LiteralToken::*,
+    OperatorToken::{self, *},
+    Token::{self, *},
+};
+
+// Identifiers borrow from the source code, see `lexer::Token`
+pub type ActionT = Action<Option<Token<'static>>, anyhow::Error>;
+pub type LexerT = Lexer<Option<Token<'static>>, anyhow::Error>;
+
+fn constant(x: Token<'static>) -> ActionT {
+    Action::C(Some(x))
+}
+
+macro_rules! keyword {
+    ($x:ident) => {
+        (casey::lower!(stringify!($x)), constant(Keyword($x)))
+    };
+}
+
+fn op(x: OperatorToken) -> ActionT {
+    constant(Operator(x))
+}
+
+fn integer_literal(c: &Captures) -> anyhow::Result<Option<Token<'static>>> {
+    use IntegerToken::*;
+    let group = |i| c.get(i).ok_or_else(|| anyhow!("integer_literal"));
+    let t = std::str::from_utf8(group(1)?)?;
+    let k: i64 = t.parse()?;
+    let x = match group(2)? {
+        b"" | b"l" => Ok(L(k as i32)),
+        b"ll" => Ok(LL(k as i64)),
+        b"u" | b"ul" | b"lu" => Ok(UL(k as u32)),
+        b"ull" | b"llu" => Ok(ULL(k as u64)),
+        _ => Err(anyhow!("integer_literal {}", t)),
+    };
+    Ok(Some(Literal(LInt(x?))))
+}
+
+// Mantissa, exponent and suffix come in as groups 1 to 3
+fn float_literal(c: &Captures) -> anyhow::Result<Option<Token<'static>>> {
+    use FloatToken::*;
+    let m = c.get(1).ok_or_else(|| anyhow!("float_literal"))?;
+    let m = std::str::from_utf8(m)?;
+    let e: i32 = match c.get(2) {
+        Some(e) => std::str::from_utf8(&e[1..])?.parse()?,
+        None => 0,
+    };
+    let t = format!("{}e{}", m, e);
+    let x = match c.get(3) {
+        Some(b"f" | b"F") => F(t.parse()?),
+        Some(_) => L(t.parse()?),
+        None => D(t.parse()?),
+    };
+    Ok(Some(Literal(LFloat(x))))
+}
+
+fn char_literal(s: &[u8]) -> anyhow::Result<Option<Token<'static>>> {
+    let t = &s[1..s.len() - 1];
+    let x = match t {
+        [b'\\', b'x', h @ ..] => {
+            u8::from_str_radix(std::str::from_utf8(h)?, 16)?
+        }
+        [b'\\', o @ ..] if o[0].is_ascii_digit() => {
+            u8::from_str_radix(std::str::from_utf8(o)?, 8)?
+        }
+        [b'\\', x] => match x {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0C,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0B,
+            _ => *x,
+        },
+        // A single character, in UTF-8
+        _ => {
+            let mut v = std::str::from_utf8(t)?.chars();
+            match (v.next(), v.next()) {
+                (Some(x), None) => return Ok(Some(Literal(LChar(x.into())))),
+                _ => return Err(anyhow!("char_literal {:?}", s)),
+            }
+        }
+    };
+    Ok(Some(Literal(LChar(x.into()))))
+}
+
+// Whole characters of the source, so still UTF-8
+fn identifier(s: &[u8]) -> anyhow::Result<Option<Token<'_>>> {
+    Ok(Some(Identifier(std::str::from_utf8(s)?)))
+}
+
+pub fn rules() -> Vec<(&'static str, ActionT)> {
+    vec![
+        (r"//.*\n|/\*([^\*]|\*+[^\*/])*\*+/|\s+", Action::C(None)),
+        keyword!(Auto),
+        keyword!(Break),
+        keyword!(Case),
+        keyword!(Char),
+        keyword!(Const),
+        keyword!(Continue),
+        keyword!(Default),
+        keyword!(Do),
+        keyword!(Double),
+        keyword!(Else),
+        keyword!(Enum),
+        keyword!(Extern),
+        keyword!(Float),
+        keyword!(For),
+        keyword!(Goto),
+        keyword!(If),
+        keyword!(Int),
+        keyword!(Long),
+        keyword!(Register),
+        keyword!(Return),
+        keyword!(Short),
+        keyword!(Signed),
+        keyword!(Sizeof),
+        keyword!(Static),
+        keyword!(Struct),
+        keyword!(Switch),
+        keyword!(Typedef),
+        keyword!(Union),
+        keyword!(Unsigned),
+        keyword!(Void),
+        keyword!(Volatile),
+        keyword!(While),
+        (r"\.\.\.", op(Ellipsis)),
+        (r">>=", op(ShrAsn)),
+        (r"<<=", op(ShlAsn)),
+        (r"\+=", op(AddAsn)),
+        (r"\-=", op(SubAsn)),
+        (r"\*=", op(MulAsn)),
+        (r"/=", op(DivAsn)),
+        (r"%=", op(ModAsn)),
+        (r"&=", op(AndAsn)),
+        (r"\^=", op(XorAsn)),
+        (r"\|=", op(OrAsn)),
+        (r">>", op(Shr)),
+        (r"<<", op(Shl)),
+        (r"\+\+", op(Inc)),
+        (r"\-\-", op(Dec)),
+        (r"\->", op(Ptr)),
+        (r"&&", op(And)),
+        (r"\|\|", op(Or)),
+        (r"<=", op(Le)),
+        (r">=", op(Ge)),
+        (r"==", op(Eq)),
+        (r"!=", op(Ne)),
+        (r";", op(Semicolon)),
+        (r"\{|<%", op(LBrace)),
+        (r"\}|>%", op(RBrace)),
+        (r",", op(Comma)),
+        (r":", op(Colon)),
+        (r"=", op(Assign)),
+        (r"\(", op(LParen)),
+        (r"\)", op(RParen)),
+        (r"\[|<:", op(LSqBr)),
+        (r"\]|>:", op(RSqBr)),
+        (r"\.", op(Dot)),
+        (r"&", op(BitAnd)),
+        (r"!", op(Not)),
+        (r"~", op(Tilde)),
+        (r"\-", op(Minus)),
+        (r"\+", op(Plus)),
+        (r"\*", op(Ast)),
+        (r"/", op(Div)),
+        (r"%", op(Mod)),
+        (r"<", op(Lt)),
+        (r">", op(Gt)),
+        (r"\^", op(Caret)),
+        (r"\|", op(BitOr)),
+        (r"\?", op(QnMk)),
+        // C11 allows extended characters in identifiers
+        (
+            "[a-zA-Z_\u{80}-\u{10FFFF}][\\w\u{80}-\u{10FFFF}]*",
+            Action::B(Box::new(identifier)),
+        ),
+        (r"({D}+)({E})({FS})?", Action::G(float_literal)),
+        (r"({D}*\.{D}+)({E})?({FS})?", Action::G(float_literal)),
+        (r"({D}+\.{D}*)({E})?({FS})?", Action::G(float_literal)),
+        (r"(\-?{D}+)({IS})", Action::G(integer_literal)),
+        (r"'([^'\\\n]|{ES})'", Action::F(char_literal)),
+    ]
+}
+
+// Compiles the rules, as the build script does for `super::clex`
+pub fn compile() -> anyhow::Result<LexerT> {
+    let (lexer, warnings) = LexerBuilder::new()
+        .unicode(true)
+        .define("D", r"[0-9]")
+        .define("H", r"[a-fA-F0-9]")
+        .define("E", r"[Ee][\+\-]?{D}+")
+        .define("FS", r"[fFlL]")
+        .define("IS", r"[uUlL]*")
+        .define("ES", r#"\\(['"\?\\abfnrtv]|[0-7]{1,3}|x{H}+)"#)
+        .rules(rules())
+        .build_with_warnings()
+        .map_err(|e| anyhow!("clex: {}", e))?;
+    // A misplaced rule would silently never fire
+    match warnings.first() {
+        Some(w) => Err(anyhow!("clex: {}", w)),
+        None => Ok(lexer),
+    }
+}
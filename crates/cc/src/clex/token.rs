@@ -1,3 +1,6 @@
+use lexer::Position;
+use std::ops::Range;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum KeywordToken {
     Auto,
@@ -88,7 +91,8 @@ pub enum OperatorToken {
 pub enum LiteralToken {
     LInt(IntegerToken),
     LFloat(FloatToken),
-    LChar(u8),
+    // The code point of the character
+    LChar(u32),
     LString(String),
 }
 
@@ -100,16 +104,37 @@ pub enum IntegerToken {
     ULL(u64),
 }
 
+// Suffixed `f`, unsuffixed and suffixed `l`
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum FloatToken {
     F(f32),
+    D(f64),
     L(f64),
 }
 
+/// Identifiers borrow their name from the source.
 #[derive(Clone, Debug, PartialEq)]
-pub enum Token {
+pub enum Token<'a> {
     Keyword(KeywordToken),
     Operator(OperatorToken),
     Literal(LiteralToken),
-    Identifier(String),
+    Identifier(&'a str),
+}
+
+// The lexer makes `Token<'a>` for source code that lives for `'a`
+impl lexer::Token for Token<'static> {
+    type Of<'a> = Token<'a>;
+
+    fn shorten<'a>(self) -> Token<'a> {
+        self
+    }
+}
+
+/// A token with where it was found: its byte span in the source and the
+/// position it starts at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Located<'a> {
+    pub token: Token<'a>,
+    pub span: Range<usize>,
+    pub position: Position,
 }
@@ -0,0 +1,308 @@
+use super::{
+    dfa::DFA,
+    encode::{Reader, Writer},
+    Automaton, IR,
+};
+use crate::LoadError;
+use bit_set::BitSet;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Inst {
+    Byte(BitSet, usize),
+    // Successors in order of preference
+    Fork(Vec<usize>),
+    Save(usize, usize),
+    Match,
+}
+
+// Second pass over a lexeme the DFA already matched: a Pike VM over an NFA
+// with ordered forks, recording where each capture group starts and ends.
+// Slots 2i and 2i + 1 hold the bounds of group i; group 0 is the lexeme.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CaptureNFA {
+    insts: Vec<Inst>,
+    start: usize,
+    slots: usize,
+}
+
+impl CaptureNFA {
+    pub(crate) fn new(ir: &IR) -> Self {
+        let mut nfa = Self {
+            insts: vec![Inst::Match],
+            start: 0,
+            slots: 2,
+        };
+        let f = nfa.push(Inst::Save(1, 0));
+        let q = nfa.compile(ir, f);
+        nfa.start = nfa.push(Inst::Save(0, q));
+        nfa
+    }
+
+    fn push(&mut self, inst: Inst) -> usize {
+        self.insts.push(inst);
+        self.insts.len() - 1
+    }
+
+    // Compiled back to front: returns the entry of `ir` continuing to `next`
+    fn compile(&mut self, ir: &IR, next: usize) -> usize {
+        use IR::*;
+        match ir {
+            E => next,
+            L(v) => {
+                let set = v.iter().map(|&x| x as usize).collect();
+                self.push(Inst::Byte(set, next))
+            }
+            U(v) => {
+                let v = v.iter().map(|x| self.compile(x, next)).collect();
+                self.push(Inst::Fork(v))
+            }
+            C(v) => v.iter().rev().fold(next, |q, x| self.compile(x, q)),
+            K(x) => {
+                let q = self.push(Inst::Fork(Vec::new()));
+                let s = self.compile(x, q);
+                self.insts[q] = Inst::Fork(vec![s, next]);
+                q
+            }
+            I(v) => {
+                let dfa = v
+                    .iter()
+                    .map(DFA::from_ir)
+                    .reduce(|a, b| a.intersection(&b))
+                    .expect("intersection of nothing");
+                self.embed(&dfa, next)
+            }
+            N(x) => self.embed(&DFA::from_ir(x).complement(), next),
+            T(i, x) => {
+                self.slots = self.slots.max(2 * i + 2);
+                let f = self.push(Inst::Save(2 * i + 1, next));
+                let q = self.compile(x, f);
+                self.push(Inst::Save(2 * i, q))
+            }
+        }
+    }
+
+    // Groups inside the DFA are lost, the lexeme as a whole is not
+    fn embed(&mut self, dfa: &DFA, next: usize) -> usize {
+        let base = self.insts.len();
+        for _ in 0..dfa.len() {
+            self.push(Inst::Fork(Vec::new()));
+        }
+        for i in 0..dfa.len() {
+            let mut m: BTreeMap<usize, BitSet> = BTreeMap::new();
+            for x in 0..=255u8 {
+                if let Some(j) = dfa.transition(&i, x) {
+                    m.entry(j).or_default().insert(x as usize);
+                }
+            }
+            let mut v: Vec<usize> = m
+                .into_iter()
+                .map(|(j, set)| self.push(Inst::Byte(set, base + j)))
+                .collect();
+            if dfa.category(&i).is_some() {
+                v.push(next);
+            }
+            self.insts[base + i] = Inst::Fork(v);
+        }
+        base + dfa.initial_state()
+    }
+
+    // Follows forks and saves depth first, so threads keep their priority
+    fn add(
+        &self,
+        list: &mut Vec<(usize, Vec<Option<usize>>)>,
+        seen: &mut [bool],
+        q: usize,
+        slots: Vec<Option<usize>>,
+        k: usize,
+    ) {
+        let mut stack = vec![(q, slots)];
+        while let Some((q, mut slots)) = stack.pop() {
+            if seen[q] {
+                continue;
+            }
+            seen[q] = true;
+            match &self.insts[q] {
+                Inst::Fork(v) => {
+                    stack.extend(v.iter().rev().map(|&r| (r, slots.clone())))
+                }
+                Inst::Save(i, r) => {
+                    slots[*i] = Some(k);
+                    stack.push((*r, slots));
+                }
+                Inst::Byte(..) | Inst::Match => list.push((q, slots)),
+            }
+        }
+    }
+
+    pub(crate) fn write(&self, w: &mut Writer) {
+        w.u32(self.start);
+        w.u32(self.slots);
+        w.u32(self.insts.len());
+        for inst in &self.insts {
+            match inst {
+                Inst::Byte(set, q) => {
+                    let mut m = [0u8; 32];
+                    for x in set {
+                        m[x / 8] |= 1 << (x % 8);
+                    }
+                    w.u8(0);
+                    w.bytes(&m);
+                    w.u32(*q);
+                }
+                Inst::Fork(v) => {
+                    w.u8(1);
+                    w.u32(v.len());
+                    for &q in v {
+                        w.u32(q);
+                    }
+                }
+                Inst::Save(i, q) => {
+                    w.u8(2);
+                    w.u32(*i);
+                    w.u32(*q);
+                }
+                Inst::Match => w.u8(3),
+            }
+        }
+    }
+
+    pub(crate) fn read(r: &mut Reader) -> Result<Self, LoadError> {
+        let start = r.u32()?;
+        let slots = r.u32()?;
+        let n = r.u32()?;
+        if start >= n || slots < 2 || slots % 2 != 0 || slots > 2 * n + 2 {
+            return Err(LoadError::Corrupt);
+        }
+        let mut insts = Vec::new();
+        for _ in 0..n {
+            insts.push(match r.u8()? {
+                0 => {
+                    let m = r.bytes(32)?;
+                    let set = (0..256).filter(|x| m[x / 8] >> (x % 8) & 1 == 1);
+                    Inst::Byte(set.collect(), r.index(n)?)
+                }
+                1 => {
+                    let k = r.u32()?;
+                    let v = (0..k).map(|_| r.index(n));
+                    Inst::Fork(v.collect::<Result<_, _>>()?)
+                }
+                2 => Inst::Save(r.index(slots)?, r.index(n)?),
+                3 => Inst::Match,
+                _ => return Err(LoadError::Corrupt),
+            });
+        }
+        Ok(Self {
+            insts,
+            start,
+            slots,
+        })
+    }
+
+    // Slots of the preferred way to match all of `s`
+    pub(crate) fn captures(&self, s: &[u8]) -> Option<Vec<Option<usize>>> {
+        let n = self.insts.len();
+        let mut list = Vec::new();
+        let slots = vec![None; self.slots];
+        self.add(&mut list, &mut vec![false; n], self.start, slots, 0);
+        for k in 0..=s.len() {
+            let mut next = Vec::new();
+            let mut seen = vec![false; n];
+            for (q, slots) in list {
+                match &self.insts[q] {
+                    Inst::Match if k == s.len() => return Some(slots),
+                    Inst::Byte(set, r)
+                        if k < s.len() && set.contains(s[k] as usize) =>
+                    {
+                        self.add(&mut next, &mut seen, *r, slots, k + 1)
+                    }
+                    _ => {}
+                }
+            }
+            list = next;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex::Syntax;
+
+    fn groups<'a>(r: &str, s: &'a str) -> Option<Vec<Option<&'a str>>> {
+        let syntax = Syntax {
+            unicode: false,
+            boolean: true,
+        };
+        let ir = IR::from_regex(r, syntax).unwrap();
+        let slots = CaptureNFA::new(&ir).captures(s.as_bytes())?;
+        Some(
+            slots
+                .chunks(2)
+                .map(|v| match v {
+                    [Some(i), Some(j)] => Some(&s[*i..*j]),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn capture_simple() {
+        assert_eq!(groups(r"a(b)c", "abc"), Some(vec![Some("abc"), Some("b")]));
+        assert_eq!(groups(r"a(b)c", "abd"), None);
+        assert_eq!(
+            groups(r"(a)|(b)", "b"),
+            Some(vec![Some("b"), None, Some("b")])
+        );
+        assert_eq!(
+            groups(r"(?:a)(b)", "ab"),
+            Some(vec![Some("ab"), Some("b")])
+        );
+    }
+
+    #[test]
+    fn capture_greedy() {
+        assert_eq!(
+            groups(r"(a*)(a*)", "aaa"),
+            Some(vec![Some("aaa"), Some("aaa"), Some("")])
+        );
+        assert_eq!(
+            groups(r"(a?)(a+)", "aa"),
+            Some(vec![Some("aa"), Some("a"), Some("a")])
+        );
+        assert_eq!(
+            groups(r"(\d+)(\d{2})", "12345"),
+            Some(vec![Some("12345"), Some("123"), Some("45")])
+        );
+        // The last iteration of a repeated group wins
+        assert_eq!(groups(r"(\w)+", "xyz"), Some(vec![Some("xyz"), Some("z")]));
+    }
+
+    #[test]
+    fn capture_float() {
+        let r = r"(\d*\.\d+|\d+\.\d*)([eE][\+\-]?\d+)?([fFlL])?";
+        assert_eq!(
+            groups(r, "1.5e-3f"),
+            Some(vec![Some("1.5e-3f"), Some("1.5"), Some("e-3"), Some("f")])
+        );
+        assert_eq!(
+            groups(r, ".25"),
+            Some(vec![Some(".25"), Some(".25"), None, None])
+        );
+    }
+
+    #[test]
+    fn capture_boolean() {
+        let r = r"/\*(~([\s\S]*\*/[\s\S]*))\*/";
+        assert_eq!(
+            groups(r, "/* a */"),
+            Some(vec![Some("/* a */"), Some(" a ")])
+        );
+        assert_eq!(
+            groups(r"(\w+&~(if))(\d*)", "if2"),
+            Some(vec![Some("if2"), Some("if2"), None, Some("")])
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use super::{
+    encode::{Reader, Writer},
+    SIGMA,
+};
+use crate::LoadError;
+use std::collections::HashMap;
+
+// Partition of the bytes into classes that no character set tells apart.
+// Classes are numbered by their smallest byte.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ByteClasses([u8; SIGMA]);
+
+impl ByteClasses {
+    pub(crate) fn new() -> Self {
+        Self([0; SIGMA])
+    }
+
+    pub(crate) fn get(&self, x: u8) -> usize {
+        self.0[x as usize] as usize
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.iter().max().map_or(0, |&k| k as usize + 1)
+    }
+
+    // The smallest byte of each class, in class order
+    pub(crate) fn representatives(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        for x in 0..=255u8 {
+            if self.get(x) == v.len() {
+                v.push(x);
+            }
+        }
+        v
+    }
+
+    // Splits every class by the key of its bytes
+    fn split<K: Eq + std::hash::Hash>(&mut self, key: impl Fn(u8) -> K) {
+        let mut m = HashMap::new();
+        for x in 0..=255u8 {
+            let n = m.len();
+            let k = *m.entry((self.0[x as usize], key(x))).or_insert(n);
+            self.0[x as usize] = k as u8;
+        }
+    }
+
+    pub(crate) fn add_set(&mut self, v: &[u8]) {
+        let mut set = [false; SIGMA];
+        for &x in v {
+            set[x as usize] = true;
+        }
+        self.split(|x| set[x as usize]);
+    }
+
+    pub(crate) fn add_classes(&mut self, other: &Self) {
+        self.split(|x| other.get(x));
+    }
+
+    pub(crate) fn write(&self, w: &mut Writer) {
+        w.bytes(&self.0);
+    }
+
+    pub(crate) fn read(r: &mut Reader) -> Result<Self, LoadError> {
+        let v: [u8; SIGMA] = r.bytes(SIGMA)?.try_into().unwrap();
+        // Each class must first appear right after the previous one
+        let mut k = 0;
+        for &x in &v {
+            if x as usize > k {
+                return Err(LoadError::Corrupt);
+            }
+            if x as usize == k {
+                k += 1;
+            }
+        }
+        Ok(Self(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes_split() {
+        let mut c = ByteClasses::new();
+        assert_eq!(c.len(), 1);
+        c.add_set(b"0123456789");
+        c.add_set(b"abcdef0123456789");
+        assert_eq!(c.len(), 3);
+        assert_eq!(c.representatives(), vec![0, b'0', b'a']);
+        assert_eq!(c.get(b'5'), c.get(b'0'));
+        assert_eq!(c.get(b'f'), c.get(b'a'));
+        assert_eq!(c.get(b'g'), c.get(0xFF));
+        let mut d = ByteClasses::new();
+        d.add_set(b"xyz");
+        c.add_classes(&d);
+        assert_eq!(c.len(), 4);
+        assert_eq!(c.get(b'x'), 3);
+        assert_ne!(c.get(b'w'), c.get(b'x'));
+    }
+}
@@ -0,0 +1,149 @@
+use super::{dfa::DFA, Automaton, Category};
+use std::fmt::Write;
+
+// Smallest unsigned type holding every value up to `max`
+fn int_type(max: usize) -> &'static str {
+    if max <= u8::MAX as usize {
+        "u8"
+    } else if max <= u16::MAX as usize {
+        "u16"
+    } else {
+        "u32"
+    }
+}
+
+// `[a, b, ...]` on the current line if it fits in 80 columns after
+// `used` of them, otherwise wrapped one level deeper than `indent`
+fn array(v: &[usize], indent: usize, used: usize) -> String {
+    let items: Vec<String> = v.iter().map(|x| x.to_string()).collect();
+    let line = format!("[{}]", items.join(", "));
+    if used + line.len() + 2 <= 80 {
+        return line;
+    }
+    let mut out = String::from("[\n");
+    let mut line = String::new();
+    for item in items {
+        if indent + 4 + line.len() + item.len() + 1 > 80 {
+            writeln!(out, "{:w$}{}", "", line.trim_end(), w = indent + 4)
+                .unwrap();
+            line.clear();
+        }
+        line += &item;
+        line += ", ";
+    }
+    writeln!(out, "{:w$}{}", "", line.trim_end(), w = indent + 4).unwrap();
+    write!(out, "{:indent$}]", "").unwrap();
+    out
+}
+
+// Rust source of a table-driven scanner for the DFA, using only core.
+// Category c of the DFA stands for rule `rules[c]`.
+pub(crate) fn rust(dfa: &DFA, rules: &[usize]) -> String {
+    let classes = dfa.classes();
+    let reps = classes.representatives();
+    let n = dfa.len();
+    let state = int_type(n);
+    let class = int_type(reps.len() - 1);
+    let rule = int_type(rules.iter().max().map_or(0, |&r| r + 1));
+    let mut out = String::new();
+    let mut table = |doc: &str, decl: String, body: String| {
+        writeln!(out, "/// {}", doc).unwrap();
+        writeln!(out, "{}{};\n", decl, body).unwrap();
+    };
+
+    let decl = format!("pub static CLASSES: [{}; 256] = ", class);
+    let v: Vec<usize> = (0..=255u8).map(|x| classes.get(x)).collect();
+    let body = array(&v, 0, decl.len());
+    table("Byte class of each byte.", decl, body);
+
+    let decl = format!("pub static ACCEPT: [{}; {}] = ", rule, n);
+    let v: Vec<usize> = (0..n)
+        .map(|q| dfa.category(&q).map_or(0, |Category(c)| rules[c] + 1))
+        .collect();
+    let body = array(&v, 0, decl.len());
+    table(
+        "Rule accepted in each state, plus one; 0 if none.",
+        decl,
+        body,
+    );
+
+    let k = reps.len();
+    let decl = format!("pub static NEXT: [[{}; {}]; {}] = ", state, k, n);
+    let mut body = String::from("[\n");
+    for q in 0..n {
+        let v: Vec<usize> = reps
+            .iter()
+            .map(|&x| dfa.transition(&q, x).map_or(0, |t| t + 1))
+            .collect();
+        writeln!(body, "    {},", array(&v, 4, 4)).unwrap();
+    }
+    body += "]";
+    table(
+        "Next state by state and byte class, plus one; 0 if none.",
+        decl,
+        body,
+    );
+
+    format!(
+        "// Generated by `Lexer::to_rust`, do not edit.\n\n{}{}",
+        out, SCANNER
+    )
+}
+
+const SCANNER: &str = "\
+/// The rule and length of the longest match at the start of `s`. On a tie
+/// the earlier rule wins.
+pub fn longest_match(s: &[u8]) -> Option<(usize, usize)> {
+    let mut q = 0;
+    let mut r = None;
+    if ACCEPT[q] != 0 {
+        r = Some((ACCEPT[q] as usize - 1, 0));
+    }
+    for (i, &x) in s.iter().enumerate() {
+        match NEXT[q][CLASSES[x as usize] as usize] {
+            0 => break,
+            t => q = t as usize - 1,
+        }
+        if ACCEPT[q] != 0 {
+            r = Some((ACCEPT[q] as usize - 1, i + 1));
+        }
+    }
+    r
+}
+";
+
+#[cfg(test)]
+mod tests {
+    use super::super::codegen_example as example;
+    use crate::{Action, Lexer, LexerBuilder};
+
+    fn lexer() -> Lexer<usize, ()> {
+        LexerBuilder::new()
+            .rule(r"if|else", Action::C(0))
+            .rule(r"\l\w*", Action::C(1))
+            .rule(r"\d+", Action::C(2))
+            .rule(r"\s+", Action::C(3))
+            .rule(r"\.\.\.|\.", Action::C(4))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn codegen_example() {
+        // Regenerate with `lexer().to_rust()` when the output changes
+        let s = lexer().to_rust().unwrap();
+        assert_eq!(s, include_str!("codegen_example.rs"));
+    }
+
+    #[test]
+    fn codegen_same_as_lexer() {
+        let lexer = lexer();
+        let inputs = ["if", "iffy", "else1", "42x", "  \n", "..", "...", "-"];
+        for s in inputs {
+            let expected = lexer
+                .run(s.as_bytes())
+                .map(|(x, t)| (x.unwrap(), s.len() - t.len()));
+            assert_eq!(example::longest_match(s.as_bytes()), expected, "{}", s);
+        }
+    }
+}
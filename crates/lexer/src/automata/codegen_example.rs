@@ -0,0 +1,55 @@
+// Generated by `Lexer::to_rust`, do not edit.
+
+/// Byte class of each byte.
+pub static CLASSES: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3, 0, 0, 0, 0, 0, 0, 0, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4,
+    4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 0, 0, 0, 0, 4, 0, 4, 4, 4,
+    4, 5, 6, 4, 4, 7, 4, 4, 8, 4, 4, 4, 4, 4, 4, 9, 4, 4, 4, 4, 4, 4, 4, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0,
+];
+
+/// Rule accepted in each state, plus one; 0 if none.
+pub static ACCEPT: [u8; 12] = [0, 4, 5, 3, 2, 2, 2, 0, 2, 1, 5, 2];
+
+/// Next state by state and byte class, plus one; 0 if none.
+pub static NEXT: [[u8; 10]; 12] = [
+    [0, 2, 3, 4, 5, 6, 5, 7, 5, 5],
+    [0, 2, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 8, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 4, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 5, 5, 5, 5, 5, 5, 5],
+    [0, 0, 0, 5, 5, 5, 5, 5, 9, 5],
+    [0, 0, 0, 5, 5, 5, 10, 5, 5, 5],
+    [0, 0, 11, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 5, 5, 5, 5, 5, 5, 12],
+    [0, 0, 0, 5, 5, 5, 5, 5, 5, 5],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 5, 5, 10, 5, 5, 5, 5],
+];
+
+/// The rule and length of the longest match at the start of `s`. On a tie
+/// the earlier rule wins.
+pub fn longest_match(s: &[u8]) -> Option<(usize, usize)> {
+    let mut q = 0;
+    let mut r = None;
+    if ACCEPT[q] != 0 {
+        r = Some((ACCEPT[q] as usize - 1, 0));
+    }
+    for (i, &x) in s.iter().enumerate() {
+        match NEXT[q][CLASSES[x as usize] as usize] {
+            0 => break,
+            t => q = t as usize - 1,
+        }
+        if ACCEPT[q] != 0 {
+            r = Some((ACCEPT[q] as usize - 1, i + 1));
+        }
+    }
+    r
+}
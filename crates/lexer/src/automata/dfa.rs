@@ -1,42 +1,51 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use super::Automaton;
-use super::{SIGMA, nfa::NFA, Category};
+use super::{
+    classes::ByteClasses,
+    dot,
+    encode::{Reader, Writer},
+    nfa::{NFABuilder, NFA},
+    Category, IR,
+};
+use crate::LoadError;
 use bit_set::BitSet;
 
-#[derive(Clone, Copy, Debug)]
+// Transitions are indexed by byte class
+#[derive(Clone, Debug, PartialEq, Eq)]
 struct DFANode {
     c: Option<Category>,
-    t: [Option<usize>; SIGMA],
+    t: Vec<Option<usize>>,
 }
 
 impl DFANode {
     fn new(nfa: &NFA, s: &BitSet) -> Self {
         Self {
             c: nfa.category(s),
-            t: [None; SIGMA],
+            t: vec![None; nfa.classes().len()],
         }
     }
 }
 
-pub(crate) struct DFA(Vec<DFANode>);
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DFA(Vec<DFANode>, ByteClasses);
 
 impl DFA {
-    fn powerset_construction(nfa: &NFA) -> Self {
+    // Also returns the NFA states making up each DFA state
+    fn powerset_construction(nfa: &NFA) -> (Self, Vec<BitSet>) {
         let s = nfa.initial_state();
         let mut d = vec![DFANode::new(nfa, &s)];
         let mut v = vec![s.clone()];
 
-        // Performance issue with email regex
         let mut m = HashMap::from([(s, 0)]);
 
         for i in 0.. {
             if i >= v.len() {
                 break;
             }
-            for x in 0..=255u8 {
-                if let Some(u) = nfa.transition(&v[i], x) {
-                    d[i].t[x as usize] = match m.get(&u) {
+            for (x, u) in nfa.transitions(&v[i]).into_iter().enumerate() {
+                if let Some(u) = u {
+                    d[i].t[x] = match m.get(&u) {
                         None => {
                             let k = v.len();
                             d.push(DFANode::new(nfa, &u));
@@ -49,9 +58,11 @@ impl DFA {
                 }
             }
         }
-        DFA(d)
+        (DFA(d, nfa.classes().clone()), v)
     }
 
+    // Reference for hopcroft, quadratic in the number of states
+    #[cfg(test)]
     fn myhill_nerode(self) -> Self {
         use std::cmp::Ordering::*;
 
@@ -64,7 +75,7 @@ impl DFA {
             for i in 0..n {
                 for j in 0..i {
                     if !mark[i][j] {
-                        let markable = (0..SIGMA).any(|x| {
+                        let markable = (0..self.1.len()).any(|x| {
                             match (self.0[i].t[x], self.0[j].t[x]) {
                                 (Some(a), Some(b)) => match a.cmp(&b) {
                                     Less => mark[b][a],
@@ -109,11 +120,308 @@ impl DFA {
                 d.push(DFANode { c, t });
             }
         }
-        DFA(d)
+        DFA(d, self.1)
+    }
+
+    // Partition refinement, O(n log n) splits per symbol. Missing
+    // transitions go to a sink kept apart from every state, so that states
+    // are merged exactly when myhill_nerode would merge them.
+    fn hopcroft(self) -> Self {
+        let n = self.0.len();
+        let mut rev: Vec<Vec<(usize, usize)>> = vec![Vec::new(); n + 1];
+        for (i, e) in self.0.iter().enumerate() {
+            for (x, t) in e.t.iter().enumerate() {
+                rev[t.unwrap_or(n)].push((x, i));
+            }
+        }
+        rev[n].extend((0..self.1.len()).map(|x| (x, n)));
+
+        let mut blocks: Vec<Vec<usize>> = vec![vec![n]];
+        let mut block = vec![0; n + 1];
+        let mut m = HashMap::new();
+        for (i, e) in self.0.iter().enumerate() {
+            let k = *m.entry(e.c.map(|Category(c)| c)).or_insert_with(|| {
+                blocks.push(Vec::new());
+                blocks.len() - 1
+            });
+            blocks[k].push(i);
+            block[i] = k;
+        }
+
+        let mut work: Vec<usize> = (0..blocks.len()).collect();
+        let mut pending = vec![true; blocks.len()];
+        while let Some(a) = work.pop() {
+            pending[a] = false;
+            let mut pre = vec![Vec::new(); self.1.len()];
+            for &j in &blocks[a] {
+                for &(x, i) in &rev[j] {
+                    pre[x].push(i);
+                }
+            }
+            for v in pre {
+                let mut hit: HashMap<usize, Vec<usize>> = HashMap::new();
+                for i in v {
+                    hit.entry(block[i]).or_default().push(i);
+                }
+                for (b, v) in hit {
+                    if v.len() == blocks[b].len() {
+                        continue;
+                    }
+                    let k = blocks.len();
+                    for &i in &v {
+                        block[i] = k;
+                    }
+                    blocks[b].retain(|&i| block[i] == b);
+                    blocks.push(v);
+                    if pending[b] || blocks[k].len() < blocks[b].len() {
+                        work.push(k);
+                        pending.push(true);
+                    } else {
+                        work.push(b);
+                        pending[b] = true;
+                        pending.push(false);
+                    }
+                }
+            }
+        }
+
+        // Number blocks by their first state, keeping state 0 initial
+        let mut reindex = vec![None; blocks.len()];
+        let mut d = Vec::new();
+        for (i, e) in self.0.iter().enumerate() {
+            if reindex[block[i]].is_none() {
+                reindex[block[i]] = Some(d.len());
+                d.push(e.clone());
+            }
+        }
+        for e in d.iter_mut() {
+            for x in e.t.iter_mut() {
+                *x = x.and_then(|t| reindex[block[t]]);
+            }
+        }
+        DFA(d, self.1)
+    }
+
+    // Drops states from which no accepting state is reachable, so that
+    // a run stops as soon as it can no longer match
+    fn prune(self) -> Self {
+        let n = self.0.len();
+        let mut rev = vec![Vec::new(); n];
+        for (i, e) in self.0.iter().enumerate() {
+            for &j in e.t.iter().flatten() {
+                rev[j].push(i);
+            }
+        }
+        let mut live: Vec<bool> =
+            self.0.iter().map(|e| e.c.is_some()).collect();
+        let mut stack: Vec<usize> = (0..n).filter(|&i| live[i]).collect();
+        while let Some(i) = stack.pop() {
+            for &j in &rev[i] {
+                if !live[j] {
+                    live[j] = true;
+                    stack.push(j);
+                }
+            }
+        }
+        live[0] = true;
+        let mut reindex = vec![None; n];
+        for (k, i) in (0..n).filter(|&i| live[i]).enumerate() {
+            reindex[i] = Some(k);
+        }
+        let d = self
+            .0
+            .into_iter()
+            .zip(live)
+            .filter(|(_, l)| *l)
+            .map(|(mut e, _)| {
+                for x in e.t.iter_mut() {
+                    *x = x.and_then(|t| reindex[t]);
+                }
+                e
+            })
+            .collect();
+        DFA(d, self.1)
     }
 
     pub(crate) fn new(nfa: &NFA) -> Self {
-        Self::myhill_nerode(Self::powerset_construction(nfa))
+        Self::with_winners(nfa).0
+    }
+
+    // For each rule, the rules that win on the inputs it matches, itself
+    // included if it ever does. Empty for a rule that matches nothing.
+    pub(crate) fn with_winners(nfa: &NFA) -> (Self, Vec<BTreeSet<usize>>) {
+        let (dfa, sets) = Self::powerset_construction(nfa);
+        let mut w = vec![BTreeSet::new(); nfa.rules()];
+        for s in &sets {
+            let v = nfa.categories(s);
+            if let Some(&Category(c)) = v.first() {
+                for Category(i) in v {
+                    w[i].insert(c);
+                }
+            }
+        }
+        (Self::hopcroft(dfa), w)
+    }
+
+    pub(crate) fn from_ir(ir: &IR) -> Self {
+        Self::new(&NFABuilder::new().ir(ir).build())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub(crate) fn classes(&self) -> &ByteClasses {
+        &self.1
+    }
+
+    // Graphviz source, with one edge per pair of states
+    pub(crate) fn to_dot(&self, name: &str) -> String {
+        let accept: Vec<_> = self.0.iter().map(|e| e.c).collect();
+        let mut edges = Vec::new();
+        for (i, e) in self.0.iter().enumerate() {
+            let mut m: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+            for x in 0..=255u8 {
+                if let Some(j) = e.t[self.1.get(x)] {
+                    m.entry(j).or_default().push(x);
+                }
+            }
+            edges.extend(m.into_iter().map(|(j, v)| (i, j, dot::label(v))));
+        }
+        dot::graph(name, &accept, &edges)
+    }
+
+    pub(crate) fn write(&self, w: &mut Writer) {
+        self.1.write(w);
+        w.u32(self.0.len());
+        for e in &self.0 {
+            w.option(e.c.map(|Category(c)| c));
+            for &t in &e.t {
+                w.option(t);
+            }
+        }
+    }
+
+    // Categories must be below `rules`
+    pub(crate) fn read(
+        r: &mut Reader,
+        rules: usize,
+    ) -> Result<Self, LoadError> {
+        let classes = ByteClasses::read(r)?;
+        let n = r.u32()?;
+        if n == 0 {
+            return Err(LoadError::Corrupt);
+        }
+        let mut d = Vec::new();
+        for _ in 0..n {
+            let c = r.option(rules)?.map(Category);
+            let t = (0..classes.len())
+                .map(|_| r.option(n))
+                .collect::<Result<_, _>>()?;
+            d.push(DFANode { c, t });
+        }
+        Ok(DFA(d, classes))
+    }
+
+    // Accepts what both accept; categories are collapsed into Category(0)
+    pub(crate) fn intersection(&self, other: &Self) -> Self {
+        let mut classes = self.1.clone();
+        classes.add_classes(&other.1);
+        let reps = classes.representatives();
+        let mut d = Vec::new();
+        let mut v = vec![(0, 0)];
+        let mut m = HashMap::from([((0, 0), 0)]);
+        let mut i = 0;
+        while i < v.len() {
+            let (a, b) = v[i];
+            let c = self.0[a].c.and(other.0[b].c).map(|_| Category(0));
+            d.push(DFANode {
+                c,
+                t: vec![None; reps.len()],
+            });
+            for (x, &y) in reps.iter().enumerate() {
+                if let (Some(p), Some(q)) =
+                    (self.transition(&a, y), other.transition(&b, y))
+                {
+                    let k = *m.entry((p, q)).or_insert_with(|| {
+                        v.push((p, q));
+                        v.len() - 1
+                    });
+                    d[i].t[x] = Some(k);
+                }
+            }
+            i += 1;
+        }
+        Self::hopcroft(DFA(d, classes).prune())
+    }
+
+    // A shortest string on which `keep` holds of whether each automaton
+    // accepts it, from a breadth-first search of their product. A missing
+    // transition leads to a state accepting nothing, so `keep` must not
+    // hold when neither accepts.
+    pub(crate) fn witness(
+        &self,
+        other: &Self,
+        keep: impl Fn(bool, bool) -> bool,
+    ) -> Option<Vec<u8>> {
+        let mut classes = self.1.clone();
+        classes.add_classes(&other.1);
+        let reps = classes.representatives();
+        let accepts = |d: &Self, q: Option<usize>| {
+            q.is_some_and(|q| d.0[q].c.is_some())
+        };
+        let start = (Some(0), Some(0));
+        // Each pair, with the pair and byte it was first reached from
+        let mut v = vec![(start, None)];
+        let mut m = HashMap::from([(start, 0)]);
+        let mut i = 0;
+        while i < v.len() {
+            let ((a, b), _) = v[i];
+            if keep(accepts(self, a), accepts(other, b)) {
+                let mut s = Vec::new();
+                let mut j = i;
+                while let (_, Some((k, x))) = v[j] {
+                    s.push(x);
+                    j = k;
+                }
+                s.reverse();
+                return Some(s);
+            }
+            for &y in &reps {
+                let p = a.and_then(|a| self.transition(&a, y));
+                let q = b.and_then(|b| other.transition(&b, y));
+                if (p, q) != (None, None) {
+                    m.entry((p, q)).or_insert_with(|| {
+                        v.push(((p, q), Some((i, y))));
+                        v.len() - 1
+                    });
+                }
+            }
+            i += 1;
+        }
+        None
+    }
+
+    // Accepts every byte string the automaton rejects. The states from
+    // which the automaton accepted everything are dead here, and pruned.
+    pub(crate) fn complement(&self) -> Self {
+        let dead = self.0.len();
+        let mut d: Vec<DFANode> = self
+            .0
+            .iter()
+            .map(|e| DFANode {
+                c: match e.c {
+                    Some(_) => None,
+                    None => Some(Category(0)),
+                },
+                t: e.t.iter().map(|t| t.or(Some(dead))).collect(),
+            })
+            .collect();
+        d.push(DFANode {
+            c: Some(Category(0)),
+            t: vec![Some(dead); self.1.len()],
+        });
+        Self::hopcroft(DFA(d, self.1.clone()).prune())
     }
 }
 
@@ -125,7 +433,7 @@ impl Automaton for DFA {
     }
 
     fn transition(&self, q: &Self::State, x: u8) -> Option<Self::State> {
-        self.0[*q].t[x as usize]
+        self.0[*q].t[self.1.get(x)]
     }
 
     fn category(&self, q: &Self::State) -> Option<Category> {
@@ -139,6 +447,7 @@ mod tests {
         automata::{
             dfa::DFA,
             nfa::NFABuilder,
+            Automaton, Category,
             IR::{self, *}, ParserAutomaton,
         },
         combinator::Parser,
@@ -206,4 +515,124 @@ mod tests {
             assert_eq!(p.accept(s.as_bytes()), x % 3 == 0, "s: {}", s);
         }
     }
+
+    #[test]
+    fn dfa_hopcroft() {
+        let regexes = [
+            r"(\w)+(\.(\w)+)?@(\w|\-)+\.(\w)+",
+            r"[0-7]{1,3}|\d+[uUlL]*|0[xX]\h+",
+            r"(a|b)*abb(a|b){3}",
+            r"//.*\n|/\*~([\s\S]*\*/[\s\S]*)\*/",
+            r"\l\w*&~(if|else)",
+        ];
+        let irs = [ir_simple_1(), ir_simple_2(), ir()]
+            .into_iter()
+            .chain(regexes.iter().map(|r| r.parse().unwrap()));
+        for ir in irs {
+            let nfa = NFABuilder::new().ir(&ir).build();
+            let d = DFA::powerset_construction(&nfa).0;
+            assert_eq!(d.clone().hopcroft(), d.clone().myhill_nerode());
+            let d = d.prune();
+            assert_eq!(d.clone().hopcroft(), d.myhill_nerode());
+        }
+        let nfa = NFABuilder::new()
+            .ir(&r"\.\.\.".parse().unwrap())
+            .ir(&r"\.".parse().unwrap())
+            .ir(&r"\w+".parse().unwrap())
+            .ir(&r"if".parse().unwrap())
+            .build();
+        let d = DFA::powerset_construction(&nfa).0.prune();
+        assert_eq!(d.clone().hopcroft(), d.myhill_nerode());
+        assert_eq!(DFA::new(&NFABuilder::new().ir(&ir()).build()).len(), 3);
+    }
+
+    #[test]
+    fn dfa_classes() {
+        let dfa = DFA::from_ir(&ir_simple_2());
+        assert_eq!(dfa.classes().len(), 4);
+        assert_eq!(dfa.0[0].t.len(), 4);
+        let ir: IR = r"[a-zA-Z_]\w*|\d+|[\+\-]".parse().unwrap();
+        let dfa = DFA::from_ir(&ir);
+        assert_eq!(dfa.classes().len(), 4);
+        let p = ParserAutomaton(dfa);
+        assert!(p.accept("_x9".as_bytes()));
+        assert!(p.accept("42".as_bytes()));
+        assert!(!p.accept("4a".as_bytes()));
+        assert!(!p.accept(&[0xFF]));
+        // Operands of an intersection keep their distinctions
+        let dfa = DFA::from_ir(&ir).intersection(&DFA::from_ir(&ir_simple_2()));
+        assert_eq!(dfa.classes().len(), 7);
+        assert!(ParserAutomaton(dfa).accept("abc".as_bytes()));
+    }
+
+    #[test]
+    fn dfa_intersection() {
+        // multiples of 3 in binary, and strings ending in 0
+        let even = C(vec![K(Box::new(L(vec![b'0', b'1']))), L(vec![b'0'])]);
+        let dfa = DFA::from_ir(&ir()).intersection(&DFA::from_ir(&even));
+        let p = ParserAutomaton(dfa);
+        for x in 1..100 {
+            let s = format!("{:b}", x);
+            assert_eq!(p.accept(s.as_bytes()), x % 6 == 0, "s: {}", s);
+        }
+    }
+
+    #[test]
+    fn dfa_complement() {
+        let dfa = DFA::from_ir(&ir_simple_2()).complement();
+        assert_eq!(dfa.0.len(), 5);
+        let p = ParserAutomaton(dfa);
+        assert!(p.accept("".as_bytes()));
+        assert!(!p.accept("abc".as_bytes()));
+        assert!(p.accept("abcd".as_bytes()));
+        assert!(p.accept("ab".as_bytes()));
+        assert!(p.accept(&[0xFF, 0x00]));
+    }
+
+    #[test]
+    fn dfa_witness() {
+        let dfa = |r: &str| DFA::from_ir(&r.parse().unwrap());
+        let (a, b) = (dfa(r"(ab)*a"), dfa(r"a(ba)*"));
+        assert_eq!(a.witness(&b, |x, y| x != y), None);
+        let (a, b) = (dfa(r"\d+"), dfa(r"\w+"));
+        assert_eq!(a.witness(&b, |x, y| x && !y), None);
+        assert_eq!(b.witness(&a, |x, y| x && !y), Some(b"A".to_vec()));
+        assert_eq!(a.witness(&b, |x, y| x && y), Some(b"0".to_vec()));
+        // The shortest of the strings only one accepts
+        let (a, b) = (dfa(r"a*"), dfa(r"(aa)*|aaaa*"));
+        assert_eq!(a.witness(&b, |x, y| x != y), Some(b"a".to_vec()));
+        let (a, b) = (dfa(r"a{3}b"), dfa(r"ab|a{3}c"));
+        assert_eq!(a.witness(&b, |x, y| x && y), None);
+        assert_eq!(a.witness(&b, |x, y| x != y), Some(b"ab".to_vec()));
+    }
+
+    #[test]
+    fn dfa_longest_accepted_prefix() {
+        // After `abc` the run is alive but not accepting, and dies at `x`
+        let lit = |s: &[u8]| C(s.iter().map(|&x| L(vec![x])).collect());
+        let ir = U(vec![lit(b"ab"), lit(b"abcd")]);
+        let p = ParserAutomaton(DFA::from_ir(&ir));
+        let s = "abcx".as_bytes();
+        assert_eq!(p.run(s), Some((Category(0), &s[2..])));
+        assert_eq!(p.scan(s), (Some((Category(0), 2)), false));
+        assert_eq!(p.run("abc".as_bytes()).map(|(_, t)| t.len()), Some(1));
+    }
+
+    #[test]
+    fn dfa_prune() {
+        // Anything without b: no run survives past a b
+        let any = K(Box::new(L((0..=255).collect())));
+        let b = C(vec![any.clone(), L(vec![b'b']), any]);
+        let not_b = DFA::from_ir(&b).complement();
+        let q = not_b.initial_state();
+        assert!(not_b.transition(&q, b'c').is_some());
+        assert!(not_b.transition(&q, b'b').is_none());
+        let ir = C(vec![L(vec![b'a']), N(Box::new(b))]);
+        let nfa = NFABuilder::new().ir(&ir).build();
+        let dfa = DFA::new(&nfa);
+        assert_eq!(dfa.0.len(), 2);
+        let q = dfa.transition(&dfa.initial_state(), b'a').unwrap();
+        assert!(dfa.transition(&q, b'c').is_some());
+        assert!(dfa.transition(&q, b'b').is_none());
+    }
 }
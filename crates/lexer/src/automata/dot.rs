@@ -0,0 +1,139 @@
+use super::{nfa::ranges, Category};
+use std::fmt::Write;
+
+// Byte sets that get their regex shorthand as a label
+const SHORTHANDS: [(&str, &[(u8, u8)]); 5] = [
+    (r"\d", &[(b'0', b'9')]),
+    (r"\h", &[(b'0', b'9'), (b'A', b'F'), (b'a', b'f')]),
+    (r"\l", &[(b'A', b'Z'), (b'_', b'_'), (b'a', b'z')]),
+    (
+        r"\w",
+        &[(b'0', b'9'), (b'A', b'Z'), (b'_', b'_'), (b'a', b'z')],
+    ),
+    (r"\s", &[(b'\t', b'\n'), (b'\r', b'\r'), (b' ', b' ')]),
+];
+
+fn byte(x: u8) -> String {
+    match x {
+        b' ' => String::from(r"\x20"),
+        b'-' | b'[' | b']' | b'^' => format!(r"\{}", x as char),
+        _ => x.escape_ascii().to_string(),
+    }
+}
+
+fn runs(v: Vec<u8>) -> String {
+    ranges(v)
+        .into_iter()
+        .map(|r| match r.end() - r.start() {
+            0 => byte(*r.start()),
+            1 => byte(*r.start()) + &byte(*r.end()),
+            _ => format!("{}-{}", byte(*r.start()), byte(*r.end())),
+        })
+        .collect()
+}
+
+// Edge label of a set of bytes: a shorthand, runs of bytes, or the runs
+// of its complement when that is shorter
+pub(crate) fn label(mut v: Vec<u8>) -> String {
+    v.sort_unstable();
+    v.dedup();
+    for (name, rs) in SHORTHANDS {
+        if v.iter().copied().eq(rs.iter().flat_map(|&(a, b)| a..=b)) {
+            return name.to_string();
+        }
+    }
+    match v.len() {
+        256 => String::from("any"),
+        n if n > 128 => {
+            let w = (0..=255u8).filter(|x| v.binary_search(x).is_err());
+            format!("[^{}]", runs(w.collect()))
+        }
+        _ => runs(v),
+    }
+}
+
+// Escapes a label for a quoted DOT string
+fn quote(s: &str) -> String {
+    s.replace('\\', r"\\").replace('"', "\\\"")
+}
+
+// The graph, called `name`, starts at state 0; accepting states are
+// double circles labeled with the rule they accept
+pub(crate) fn graph(
+    name: &str,
+    accept: &[Option<Category>],
+    edges: &[(usize, usize, String)],
+) -> String {
+    let mut out = format!("digraph \"{}\" {{\n", quote(name));
+    out += "    rankdir=LR;\n";
+    out += "    node [shape=circle];\n";
+    out += "    start [shape=point];\n    start -> 0;\n";
+    for (i, c) in accept.iter().enumerate() {
+        if let Some(Category(c)) = c {
+            writeln!(
+                out,
+                "    {} [shape=doublecircle, label=\"{}\\nrule {}\"];",
+                i, i, c
+            )
+            .unwrap();
+        }
+    }
+    for (i, j, s) in edges {
+        writeln!(out, "    {} -> {} [label=\"{}\"];", i, j, quote(s)).unwrap();
+    }
+    out += "}\n";
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::automata::{dfa::DFA, nfa::NFABuilder, IR};
+
+    #[test]
+    fn dot_label() {
+        assert_eq!(label((b'0'..=b'9').rev().collect()), r"\d");
+        assert_eq!(label((b'a'..=b'z').collect()), "a-z");
+        assert_eq!(label(b"ab-".to_vec()), r"\-ab");
+        assert_eq!(label(b"x\" \n".to_vec()), r#"\n\x20\"x"#);
+        assert_eq!(label((0..=255).collect()), "any");
+        assert_eq!(
+            label((0..=255).filter(|&x| x != b'\n').collect()),
+            r"[^\n]"
+        );
+    }
+
+    #[test]
+    fn dot_dfa() {
+        let ir = r"\d+|a".parse::<IR>().unwrap();
+        assert_eq!(
+            DFA::from_ir(&ir).to_dot("INITIAL"),
+            "\
+digraph \"INITIAL\" {
+    rankdir=LR;
+    node [shape=circle];
+    start [shape=point];
+    start -> 0;
+    1 [shape=doublecircle, label=\"1\\nrule 0\"];
+    2 [shape=doublecircle, label=\"2\\nrule 0\"];
+    0 -> 1 [label=\"\\\\d\"];
+    0 -> 2 [label=\"a\"];
+    1 -> 1 [label=\"\\\\d\"];
+}
+"
+        );
+    }
+
+    #[test]
+    fn dot_nfa() {
+        let a = r"a".parse::<IR>().unwrap();
+        let b = r"b*".parse::<IR>().unwrap();
+        let nfa = NFABuilder::new().ir(&a).ir(&b).build();
+        let s = nfa.to_dot("INITIAL");
+        assert!(s.contains("0 -> 1 [label=\"a\"];"), "{}", s);
+        assert!(s.contains("1 [shape=doublecircle, label=\"1\\nrule 0\"];"));
+        assert!(s.contains("[label=\"b\"]"));
+        assert!(s.contains("[label=\"ε\"]"));
+        assert_eq!(s.matches("rule 1").count(), 1);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::LoadError;
+
+// Little-endian fields of the serialized lexer
+pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+impl Writer {
+    pub(crate) fn u8(&mut self, x: u8) {
+        self.0.push(x);
+    }
+
+    pub(crate) fn u16(&mut self, x: u16) {
+        self.0.extend(x.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, x: usize) {
+        self.0.extend(u32::try_from(x).unwrap().to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, v: &[u8]) {
+        self.0.extend(v);
+    }
+
+    // 0 for None, otherwise the index plus one
+    pub(crate) fn option(&mut self, x: Option<usize>) {
+        self.u32(x.map_or(0, |i| i + 1));
+    }
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        if self.0.len() < n {
+            return Err(LoadError::Truncated);
+        }
+        let (v, t) = self.0.split_at(n);
+        self.0 = t;
+        Ok(v)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<usize, LoadError> {
+        let x = u32::from_le_bytes(self.bytes(4)?.try_into().unwrap());
+        Ok(x as usize)
+    }
+
+    // An index that must be below `n`
+    pub(crate) fn index(&mut self, n: usize) -> Result<usize, LoadError> {
+        Some(self.u32()?)
+            .filter(|&i| i < n)
+            .ok_or(LoadError::Corrupt)
+    }
+
+    pub(crate) fn option(
+        &mut self,
+        n: usize,
+    ) -> Result<Option<usize>, LoadError> {
+        match self.index(n + 1)? {
+            0 => Ok(None),
+            i => Ok(Some(i - 1)),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use super::{nfa::NFA, Automaton, Category};
+use bit_set::BitSet;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+// A cached DFA state, or an NFA state set once the cache has given up
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LazyState {
+    Cached(usize),
+    Set(BitSet),
+}
+
+#[derive(Default)]
+struct Cache {
+    sets: Vec<BitSet>,
+    categories: Vec<Option<Category>>,
+    // None until computed, Some(None) for no transition
+    t: Vec<Vec<Option<Option<usize>>>>,
+    index: HashMap<BitSet, usize>,
+    // Transitions served since the last flush
+    served: usize,
+}
+
+// Determinizes the NFA as input reaches new states. Each run takes a
+// cache of its own from a pool, so runs on other threads never see it,
+// and gives it back when done for later runs to reuse.
+pub(crate) struct LazyDFA {
+    nfa: NFA,
+    capacity: usize,
+    pool: Mutex<Vec<Cache>>,
+}
+
+impl LazyDFA {
+    pub(crate) fn new(nfa: NFA, capacity: usize) -> Self {
+        Self {
+            nfa,
+            capacity: capacity.max(2),
+            pool: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn nfa(&self) -> &NFA {
+        &self.nfa
+    }
+
+    // A panic elsewhere while the pool was locked leaves it a list of
+    // whole caches, so it is still fine to use
+    fn pool(&self) -> MutexGuard<'_, Vec<Cache>> {
+        self.pool.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn start(&self) -> LazyRun<'_> {
+        LazyRun {
+            dfa: self,
+            cache: RefCell::new(self.pool().pop().unwrap_or_default()),
+        }
+    }
+}
+
+// A run of a `LazyDFA` on its own cache, which keeps at most `capacity`
+// states. A full cache is flushed, keeping only the state in use, which
+// is the only state a run holds. If it fills up again within
+// `10 * capacity` transitions, the run simulates the NFA directly from
+// there.
+pub(crate) struct LazyRun<'a> {
+    dfa: &'a LazyDFA,
+    cache: RefCell<Cache>,
+}
+
+impl Drop for LazyRun<'_> {
+    fn drop(&mut self) {
+        let cache = std::mem::take(self.cache.get_mut());
+        self.dfa.pool().push(cache);
+    }
+}
+
+impl LazyRun<'_> {
+    // The cached state of the NFA states `s`, flushing a full cache
+    fn enter(&self, s: BitSet) -> LazyState {
+        let mut cache = self.cache.borrow_mut();
+        if !cache.index.contains_key(&s)
+            && cache.sets.len() >= self.dfa.capacity
+        {
+            *cache = Cache::default();
+        }
+        LazyState::Cached(self.add(&mut cache, s))
+    }
+
+    // `q` as its NFA states, which other runs can go on from
+    pub(crate) fn detach(&self, q: LazyState) -> LazyState {
+        match q {
+            LazyState::Cached(i) => {
+                LazyState::Set(self.cache.borrow().sets[i].clone())
+            }
+            q => q,
+        }
+    }
+
+    // A state from `detach` back in the cache of this run
+    pub(crate) fn attach(&self, q: LazyState) -> LazyState {
+        match q {
+            LazyState::Set(s) => self.enter(s),
+            q => q,
+        }
+    }
+
+    fn add(&self, cache: &mut Cache, s: BitSet) -> usize {
+        if let Some(&i) = cache.index.get(&s) {
+            return i;
+        }
+        cache.categories.push(self.dfa.nfa.category(&s));
+        cache.t.push(vec![None; self.dfa.nfa.classes().len()]);
+        cache.sets.push(s.clone());
+        cache.index.insert(s, cache.sets.len() - 1);
+        cache.sets.len() - 1
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.cache.borrow().sets.len()
+    }
+}
+
+impl Automaton for LazyRun<'_> {
+    type State = LazyState;
+
+    fn initial_state(&self) -> Self::State {
+        self.enter(self.dfa.nfa.initial_state())
+    }
+
+    fn transition(&self, q: &Self::State, x: u8) -> Option<Self::State> {
+        let (nfa, capacity) = (&self.dfa.nfa, self.dfa.capacity);
+        let i = match q {
+            LazyState::Cached(i) => *i,
+            LazyState::Set(s) => {
+                return nfa.transition(s, x).map(LazyState::Set)
+            }
+        };
+        let k = nfa.classes().get(x);
+        let mut cache = self.cache.borrow_mut();
+        cache.served += 1;
+        if let Some(j) = cache.t[i][k] {
+            return j.map(LazyState::Cached);
+        }
+        let Some(u) = nfa.transition(&cache.sets[i], x) else {
+            cache.t[i][k] = Some(None);
+            return None;
+        };
+        let mut i = i;
+        if !cache.index.contains_key(&u) && cache.sets.len() >= capacity {
+            if cache.served < 10 * capacity {
+                return Some(LazyState::Set(u));
+            }
+            let s = cache.sets.swap_remove(i);
+            *cache = Cache::default();
+            i = self.add(&mut cache, s);
+        }
+        let j = self.add(&mut cache, u);
+        cache.t[i][k] = Some(Some(j));
+        Some(LazyState::Cached(j))
+    }
+
+    fn category(&self, q: &Self::State) -> Option<Category> {
+        match q {
+            LazyState::Cached(i) => self.cache.borrow().categories[*i],
+            LazyState::Set(s) => self.dfa.nfa.category(s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        automata::{dfa::DFA, nfa::NFABuilder, ParserAutomaton, IR},
+        combinator::Parser,
+    };
+
+    fn nfa(regexes: &[&str]) -> NFA {
+        regexes
+            .iter()
+            .fold(NFABuilder::new(), |b, r| b.ir(&r.parse::<IR>().unwrap()))
+            .build()
+    }
+
+    #[test]
+    fn lazy_on_demand() {
+        let lazy = LazyDFA::new(nfa(&[r"\w+", r"\d+", r"\s+"]), 100);
+        let p = ParserAutomaton(lazy.start());
+        assert_eq!(p.0.len(), 0);
+        assert_eq!(
+            p.run("ab c".as_bytes()),
+            Some((Category(0), " c".as_bytes()))
+        );
+        // Only the states on the way were built
+        assert_eq!(p.0.len(), 3);
+        assert_eq!(p.run("12".as_bytes()), Some((Category(0), "".as_bytes())));
+        assert_eq!(p.0.len(), 5);
+    }
+
+    #[test]
+    fn lazy_same_as_eager() {
+        let regexes = [r"(a|b)*abb(a|b){3}", r"a+", r"b+", r"[ab]{1,3}"];
+        let eager = ParserAutomaton(DFA::new(&nfa(&regexes)));
+        for capacity in [2, 3, 1000] {
+            let lazy = LazyDFA::new(nfa(&regexes), capacity);
+            let lazy = ParserAutomaton(lazy.start());
+            for x in 0..(1 << 10) {
+                let s: Vec<u8> = (0..10)
+                    .map(|i| if x >> i & 1 == 1 { b'a' } else { b'b' })
+                    .collect();
+                assert_eq!(lazy.run(&s), eager.run(&s), "{:?}", s);
+            }
+            assert!(lazy.0.len() <= capacity.max(2));
+        }
+    }
+
+    #[test]
+    fn lazy_sync() {
+        fn sync<T: Send + Sync>(_: &T) {}
+        sync(&LazyDFA::new(nfa(&["a"]), 2));
+    }
+
+    #[test]
+    fn lazy_pool() {
+        let lazy = LazyDFA::new(nfa(&[r"\w+"]), 100);
+        let (a, b) = (ParserAutomaton(lazy.start()), lazy.start());
+        assert!(a.accept(b"ab"));
+        // Runs at the same time have caches of their own
+        assert_eq!((a.0.len(), b.len()), (3, 0));
+        drop((b, a));
+        assert_eq!(lazy.pool().len(), 2);
+        // A later run picks up where an earlier one left off
+        let c = lazy.start();
+        assert_eq!(c.len(), 3);
+        drop(c);
+        assert_eq!(lazy.pool().len(), 2);
+    }
+
+    #[test]
+    fn lazy_thrash() {
+        // The n-th byte from the end: exponentially many DFA states
+        let lazy = LazyDFA::new(nfa(&[r"[ab]*a[ab]{8}"]), 4);
+        let p = ParserAutomaton(lazy.start());
+        let s = "ab".repeat(50) + "abbbbbbbb";
+        assert!(p.accept(s.as_bytes()));
+        assert!(!p.accept((s + "a").as_bytes()));
+        assert!(p.0.len() <= 4);
+    }
+}
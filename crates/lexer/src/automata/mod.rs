@@ -1,6 +1,14 @@
 use super::combinator::Parser;
 
+pub(super) mod capture;
+pub(super) mod classes;
+pub(super) mod codegen;
+#[cfg(test)]
+mod codegen_example;
 pub(super) mod dfa;
+pub(super) mod dot;
+pub(super) mod encode;
+pub(super) mod lazy;
 pub(super) mod nfa;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -13,6 +21,30 @@ pub(crate) enum IR {
     U(Vec<IR>),
     C(Vec<IR>),
     K(Box<IR>),
+    // Intersection and complement, built on DFAs of the operands
+    I(Vec<IR>),
+    N(Box<IR>),
+    // Capture group, only seen by the capture NFA
+    T(usize, Box<IR>),
+}
+
+impl IR {
+    // Matches the strings this matches, read backwards
+    pub(crate) fn reverse(&self) -> IR {
+        use IR::*;
+        let all = |v: &[IR]| v.iter().map(IR::reverse).collect();
+        match self {
+            E => E,
+            L(v) => L(v.clone()),
+            U(v) => U(all(v)),
+            C(v) => C(v.iter().rev().map(IR::reverse).collect()),
+            K(x) => K(Box::new(x.reverse())),
+            // Reversal maps strings one to one, so it commutes with both
+            I(v) => I(all(v)),
+            N(x) => N(Box::new(x.reverse())),
+            T(i, x) => T(*i, Box::new(x.reverse())),
+        }
+    }
 }
 
 pub(crate) trait Automaton {
@@ -20,32 +52,75 @@ pub(crate) trait Automaton {
     fn initial_state(&self) -> Self::State;
     fn transition(&self, q: &Self::State, x: u8) -> Option<Self::State>;
     fn category(&self, q: &Self::State) -> Option<Category>;
+}
 
-    fn transition_on<'a, 'b>(
-        &'b self,
-        q: &'b Self::State,
-        s: &'a [u8],
-    ) -> Option<(Self::State, &'a [u8])> {
-        let (&x, t) = s.split_first()?;
-        let z = self.transition(q, x)?;
-        Some((z, t))
+pub(crate) struct ParserAutomaton<T: Automaton>(pub(crate) T);
+
+// A scan that ran out of input: the state it reached after `n` bytes, and
+// the category and length of the longest match among them
+pub(crate) struct Partial<S> {
+    pub(crate) q: S,
+    pub(crate) n: usize,
+    pub(crate) r: Option<(Category, usize)>,
+}
+
+impl<S> Partial<S> {
+    pub(crate) fn map<U>(self, f: impl FnOnce(S) -> U) -> Partial<U> {
+        Partial {
+            q: f(self.q),
+            n: self.n,
+            r: self.r,
+        }
     }
 }
 
-pub(crate) struct ParserAutomaton<T: Automaton>(pub(crate) T);
+impl<T: Automaton> ParserAutomaton<T> {
+    // The category and length of the longest match, and whether the
+    // automaton was still alive when `s` ran out, so that more input
+    // could make the match longer
+    pub(crate) fn scan(&self, s: &[u8]) -> (Option<(Category, usize)>, bool) {
+        match self.resume(self.begin(), s) {
+            Ok(r) => (r, false),
+            Err(p) => (p.r, true),
+        }
+    }
+
+    pub(crate) fn begin(&self) -> Partial<T::State> {
+        let q = self.0.initial_state();
+        let r = self.0.category(&q).map(|c| (c, 0));
+        Partial { q, n: 0, r }
+    }
 
+    // Goes on with `s` from where `p` ran out after its first `p.n` bytes:
+    // the longest match once the automaton dies, or where it ran out again
+    pub(crate) fn resume(
+        &self,
+        mut p: Partial<T::State>,
+        s: &[u8],
+    ) -> Result<Option<(Category, usize)>, Partial<T::State>> {
+        for &x in &s[p.n..] {
+            match self.0.transition(&p.q, x) {
+                Some(z) => p.q = z,
+                None => return Ok(p.r),
+            }
+            p.n += 1;
+            if let Some(c) = self.0.category(&p.q) {
+                p.r = Some((c, p.n));
+            }
+        }
+        Err(p)
+    }
+}
+
+// Maximal munch: the longest prefix of the input that reaches an
+// accepting state, even when the run goes on through states that do not
+// accept before it dies
 impl<T: Automaton> Parser for ParserAutomaton<T> {
     type Item = Category;
 
-    fn run<'a>(&self, mut s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
-        let mut q = self.0.initial_state();
-        let mut r = self.0.category(&q).map(|c| (c, s));
-        while let Some((z, t)) = self.0.transition_on(&q, s) {
-            q = z;
-            r = self.0.category(&q).map(|c| (c, t));
-            s = t;
-        }
-        r
+    fn run<'a>(&self, s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
+        let (c, n) = self.scan(s).0?;
+        Some((c, &s[n..]))
     }
 }
 
@@ -1,20 +1,18 @@
-use super::{SIGMA, Automaton, Category, IR};
+use super::{classes::ByteClasses, dfa::DFA, dot, Automaton, Category, IR};
+use crate::Policy;
 use bit_set::{self, BitSet};
-use std::fmt::{self, Debug, Formatter};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt::{self, Debug, Formatter},
+    ops::{Range, RangeInclusive},
+};
 
-#[derive(Clone, PartialEq, Eq)]
+// Edges are sparse: byte ranges to a state, plus epsilon moves
+#[derive(Clone, Default, PartialEq, Eq)]
 struct NFANode {
-    epsilon: BitSet,
-    t: [BitSet; SIGMA],
-}
-
-impl Default for NFANode {
-    fn default() -> Self {
-        Self {
-            epsilon: BitSet::new(),
-            t: std::array::from_fn(|_| BitSet::new()),
-        }
-    }
+    epsilon: Vec<usize>,
+    t: Vec<(RangeInclusive<u8>, usize)>,
 }
 
 impl Debug for NFANode {
@@ -22,11 +20,9 @@ impl Debug for NFANode {
         let strs = self
             .t
             .iter()
-            .enumerate()
-            .filter_map(|(i, v)| {
-                char::from_u32(i as u32)
-                    .filter(|_| !v.is_empty())
-                    .map(|c| format!("{} -> {:?}", c, v))
+            .map(|(r, j)| {
+                let (a, b) = (r.start().escape_ascii(), r.end().escape_ascii());
+                format!("{}-{} -> {}", a, b, j)
             })
             .collect::<Vec<_>>();
         f.debug_struct("NFAEntry")
@@ -36,9 +32,29 @@ impl Debug for NFANode {
     }
 }
 
+// Maximal runs of consecutive bytes
+pub(super) fn ranges(mut v: Vec<u8>) -> Vec<RangeInclusive<u8>> {
+    v.sort_unstable();
+    v.dedup();
+    let mut w: Vec<RangeInclusive<u8>> = Vec::new();
+    for x in v {
+        match w.last_mut() {
+            Some(r) if *r.end() as usize + 1 == x as usize => {
+                *r = *r.start()..=x
+            }
+            _ => w.push(x..=x),
+        }
+    }
+    w
+}
+
 pub(crate) struct NFABuilder {
     nodes: Vec<NFANode>,
     fs: BitSet,
+    classes: ByteClasses,
+    // The first state of each rule, and how far its matches run
+    starts: Vec<usize>,
+    policies: Vec<Policy>,
 }
 
 impl NFABuilder {
@@ -46,6 +62,9 @@ impl NFABuilder {
         Self {
             nodes: vec![NFANode::default()],
             fs: BitSet::new(),
+            classes: ByteClasses::new(),
+            starts: Vec::new(),
+            policies: Vec::new(),
         }
     }
 
@@ -55,31 +74,72 @@ impl NFABuilder {
     }
 
     pub(crate) fn add_ir(&mut self, ir: &IR) {
+        self.add_rule(ir, Policy::Longest);
+    }
+
+    pub(crate) fn add_rule(&mut self, ir: &IR, policy: Policy) {
+        self.starts.push(self.nodes.len());
+        self.policies.push(policy);
         let f = self.thompson(ir, 0);
         self.fs.insert(f);
     }
 
-    // complete epsilon closure for NFA instance
+    // Byte ranges become the classes they cover, now that those are final
     pub(crate) fn build(self) -> NFA {
-        let Self { mut nodes, fs } = self;
-        let n = nodes.len();
-        let mut mark = vec![true; n];
-        for (i, x) in nodes.iter_mut().enumerate() {
-            x.epsilon.insert(i);
+        let Self {
+            nodes,
+            fs,
+            classes,
+            starts,
+            policies,
+        } = self;
+        let moves = nodes
+            .iter()
+            .map(|e| {
+                let mut v: Vec<(usize, usize)> = e
+                    .t
+                    .iter()
+                    .flat_map(|(r, j)| r.clone().map(|x| (classes.get(x), *j)))
+                    .collect();
+                v.sort_unstable();
+                v.dedup();
+                v
+            })
+            .collect();
+        NFA {
+            nodes,
+            moves,
+            fs,
+            classes,
+            starts,
+            policies,
         }
-        for _ in 0..n {
-            for i in 0..n {
-                if mark[i] {
-                    let mut x = nodes[i].epsilon.clone();
-                    for j in &nodes[i].epsilon {
-                        x.union_with(&nodes[j].epsilon);
-                    }
-                    mark[i] = nodes[i].epsilon != x;
-                    nodes[i].epsilon = x;
+    }
+
+    // Copies the states of a DFA, reached from q by epsilon
+    fn embed(&mut self, dfa: &DFA, q: usize) -> usize {
+        let base = self.nodes.len();
+        let f = base + dfa.len();
+        for i in 0..dfa.len() {
+            let mut m: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+            for x in 0..=255u8 {
+                if let Some(j) = dfa.transition(&i, x) {
+                    m.entry(base + j).or_default().push(x);
                 }
             }
+            let mut e = NFANode::default();
+            for (j, v) in m {
+                e.t.extend(ranges(v).into_iter().map(|r| (r, j)));
+            }
+            if dfa.category(&i).is_some() {
+                e.epsilon.push(f);
+            }
+            self.nodes.push(e);
         }
-        NFA { nodes, fs }
+        self.nodes.push(NFANode::default());
+        self.nodes[q].epsilon.push(base + dfa.initial_state());
+        self.classes.add_classes(dfa.classes());
+        f
     }
 
     fn add_state(&mut self) -> usize {
@@ -92,14 +152,14 @@ impl NFABuilder {
         match ir {
             E => {
                 let f = self.add_state();
-                self.nodes[q].epsilon.insert(f);
+                self.nodes[q].epsilon.push(f);
                 f
             }
             L(v) => {
                 let f = self.add_state();
-                for &x in v {
-                    self.nodes[q].t[x as usize].insert(f);
-                }
+                self.classes.add_set(v);
+                let t = ranges(v.clone()).into_iter().map(|r| (r, f));
+                self.nodes[q].t.extend(t);
                 f
             }
             U(v) => {
@@ -107,13 +167,13 @@ impl NFABuilder {
                     .iter()
                     .map(|x| {
                         let s = self.add_state();
-                        self.nodes[q].epsilon.insert(s);
+                        self.nodes[q].epsilon.push(s);
                         self.thompson(x, s)
                     })
                     .collect();
                 let g = self.add_state();
                 for f in fs {
-                    self.nodes[f].epsilon.insert(g);
+                    self.nodes[f].epsilon.push(g);
                 }
                 g
             }
@@ -123,14 +183,24 @@ impl NFABuilder {
                 }
                 q
             }
+            I(v) => {
+                let dfa = v
+                    .iter()
+                    .map(DFA::from_ir)
+                    .reduce(|a, b| a.intersection(&b))
+                    .expect("intersection of nothing");
+                self.embed(&dfa, q)
+            }
+            N(x) => self.embed(&DFA::from_ir(x).complement(), q),
+            T(_, x) => self.thompson(x, q),
             K(x) => {
                 let s = self.add_state();
                 let f  = self.thompson(x, s);
                 let g = self.add_state();
-                self.nodes[q].epsilon.insert(s);
-                self.nodes[q].epsilon.insert(g);
-                self.nodes[f].epsilon.insert(s);
-                self.nodes[f].epsilon.insert(g);
+                self.nodes[q].epsilon.push(s);
+                self.nodes[q].epsilon.push(g);
+                self.nodes[f].epsilon.push(s);
+                self.nodes[f].epsilon.push(g);
                 g
             }
         }
@@ -140,22 +210,117 @@ impl NFABuilder {
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct NFA {
     nodes: Vec<NFANode>,
+    // (class, state) pairs of each state
+    moves: Vec<Vec<(usize, usize)>>,
     fs: BitSet,
+    classes: ByteClasses,
+    starts: Vec<usize>,
+    policies: Vec<Policy>,
+}
+
+impl NFA {
+    // Bytes in one class lead to the same states from every state
+    pub(crate) fn classes(&self) -> &ByteClasses {
+        &self.classes
+    }
+
+    // Depth first, visiting each state and edge at most once
+    fn closure(&self, mut stack: Vec<usize>) -> BitSet {
+        let mut s = BitSet::with_capacity(self.nodes.len());
+        while let Some(i) = stack.pop() {
+            if s.insert(i) {
+                let e = self.nodes[i].epsilon.iter();
+                stack.extend(e.filter(|&&j| !s.contains(j)));
+            }
+        }
+        s
+    }
+
+    // The states of rule c; each rule's states follow those of the last
+    fn states(&self, c: usize) -> Range<usize> {
+        let end = self.starts.get(c + 1).copied();
+        self.starts[c]..end.unwrap_or(self.nodes.len())
+    }
+
+    // The states of `q` that move on: none once a first-accept rule wins,
+    // and none of a shortest-match rule once it accepts
+    fn movers<'a>(&self, q: &'a BitSet) -> Cow<'a, BitSet> {
+        if self.policies.iter().all(|&p| p == Policy::Longest) {
+            return Cow::Borrowed(q);
+        }
+        if let Some(Category(c)) = self.category(q) {
+            if self.policies[c] == Policy::FirstAccept {
+                return Cow::Owned(BitSet::new());
+            }
+        }
+        let mut q = q.clone();
+        for Category(c) in self.categories(&q) {
+            if self.policies[c] == Policy::Shortest {
+                self.states(c).for_each(|i| {
+                    q.remove(i);
+                });
+            }
+        }
+        Cow::Owned(q)
+    }
+
+    // Successors on every class at once, in one pass over the edges
+    pub(crate) fn transitions(&self, q: &BitSet) -> Vec<Option<BitSet>> {
+        let mut v = vec![Vec::new(); self.classes.len()];
+        for i in self.movers(q).iter() {
+            for &(k, j) in &self.moves[i] {
+                v[k].push(j);
+            }
+        }
+        v.into_iter()
+            .map(|u| Some(self.closure(u)).filter(|t| !t.is_empty()))
+            .collect()
+    }
+
+    pub(crate) fn rules(&self) -> usize {
+        self.fs.len()
+    }
+
+    // Every rule accepting in `q`, unlike `category` which picks the first
+    pub(crate) fn categories(&self, q: &BitSet) -> Vec<Category> {
+        let v = self.fs.iter().enumerate().filter(|&(_, f)| q.contains(f));
+        v.map(|(c, _)| Category(c)).collect()
+    }
+
+    // Graphviz source, with byte edges to the same state merged
+    pub(crate) fn to_dot(&self, name: &str) -> String {
+        let mut accept = vec![None; self.nodes.len()];
+        for (c, f) in self.fs.iter().enumerate() {
+            accept[f] = accept[f].or(Some(Category(c)));
+        }
+        let mut edges = Vec::new();
+        for (i, e) in self.nodes.iter().enumerate() {
+            let mut m: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+            for (r, j) in &e.t {
+                m.entry(*j).or_default().extend(r.clone());
+            }
+            edges.extend(m.into_iter().map(|(j, v)| (i, j, dot::label(v))));
+            edges.extend(e.epsilon.iter().map(|&j| (i, j, "ε".to_string())));
+        }
+        dot::graph(name, &accept, &edges)
+    }
 }
 
 impl Automaton for NFA {
     type State = BitSet;
     fn initial_state(&self) -> Self::State {
-        self.nodes[0].epsilon.clone()
+        self.closure(vec![0])
     }
     fn transition(&self, q: &Self::State, x: u8) -> Option<Self::State> {
-        let mut s = BitSet::new();
-        for i in q {
-            for j in &self.nodes[i].t[x as usize] {
-                s.union_with(&self.nodes[j].epsilon);
-            }
-        }
-        Some(s).filter(|t| !t.is_empty())
+        let k = self.classes.get(x);
+        let v: Vec<usize> = self
+            .movers(q)
+            .iter()
+            .flat_map(|i| &self.moves[i])
+            .filter(|&&(c, _)| c == k)
+            .map(|&(_, j)| j)
+            .collect();
+        Some(self.closure(v)).filter(|t| !t.is_empty())
     }
     fn category(&self, q: &Self::State) -> Option<Category> {
         self.fs.iter().position(|f| q.contains(f)).map(Category)
@@ -225,4 +390,30 @@ mod tests {
             assert_eq!(p.accept(s.as_bytes()), x % 3 == 0);
         }
     }
+
+    #[test]
+    fn nfa_sparse() {
+        assert_eq!(
+            ranges(vec![b'c', b'a', b'b', b'e', b'e']),
+            vec![b'a'..=b'c', b'e'..=b'e']
+        );
+        let nfa = NFABuilder::new().ir(&L((0..=255).collect())).build();
+        assert_eq!(nfa.nodes[0].t, vec![(0..=255, 1)]);
+    }
+
+    #[test]
+    fn nfa_epsilon_cycle() {
+        // (a*|b*)* has epsilon loops through every star
+        let a = K(Box::new(L(vec![b'a'])));
+        let b = K(Box::new(L(vec![b'b'])));
+        let ir = K(Box::new(U(vec![a, K(Box::new(b))])));
+        let nfa = NFABuilder::new().ir(&ir).build();
+        let q = nfa.initial_state();
+        assert_eq!(nfa.category(&q), Some(Category(0)));
+        let r = nfa.transition(&q, b'a').unwrap();
+        assert_eq!(nfa.transitions(&q)[nfa.classes().get(b'a')], Some(r));
+        let p = ParserAutomaton(nfa);
+        assert!(p.accept("abba".as_bytes()));
+        assert!(!p.accept("abc".as_bytes()));
+    }
 }
@@ -1,4 +1,4 @@
-use std::{iter::successors, rc::Rc};
+use std::{iter::successors, ops::Range, rc::Rc};
 
 mod ptrs;
 
@@ -15,6 +15,12 @@ pub trait Parser {
     {
         Items(self, s)
     }
+    fn spans<'a>(&'a self, s: &'a [u8]) -> Spans<'a, Self>
+    where
+        Self: Sized,
+    {
+        Spans(self, s, 0)
+    }
     fn collect(self) -> Collect<Self>
     where
         Self: Sized,
@@ -76,6 +82,44 @@ pub trait Parser {
     {
         FilterMap(self, f)
     }
+    // Like `collect`, stopping after `n` items
+    fn at_most(self, n: usize) -> AtMost<Self>
+    where
+        Self: Sized,
+    {
+        AtMost(self, n)
+    }
+    // Like `zip_with` for parsers whose items are `Err` when they fail
+    // past the point of no return. `other` only runs after an `Ok`, and
+    // `f` may fail too.
+    fn try_zip_with<Q, T, U, V, K, F>(
+        self,
+        other: Q,
+        f: F,
+    ) -> TryZipWith<Self, Q, F>
+    where
+        Self: Sized + Parser<Item = Result<T, Failure<K>>>,
+        Q: Parser<Item = Result<U, Failure<K>>>,
+        F: Fn(T, U) -> Result<V, Failure<K>>,
+    {
+        TryZipWith(self, other, f)
+    }
+    // Like `collect`, stopping at the first `Err`
+    fn try_collect<T, K>(self) -> TryCollect<Self>
+    where
+        Self: Sized + Parser<Item = Result<T, Failure<K>>>,
+    {
+        TryCollect(self)
+    }
+}
+
+// Why a parser failed and where: `left` is the length of the input left
+// at that point, which the caller, holding the whole input, turns into an
+// offset
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure<K> {
+    pub left: usize,
+    pub kind: K,
 }
 
 pub struct Items<'a, P: Parser>(&'a P, &'a [u8]);
@@ -88,6 +132,20 @@ impl<'a, P: Parser> Iterator for Items<'a, P> {
         Some(x)
     }
 }
+// Like `Items`, also giving the byte range of each item in the input
+pub struct Spans<'a, P: Parser>(&'a P, &'a [u8], usize);
+impl<'a, P: Parser> Iterator for Spans<'a, P> {
+    type Item = (P::Item, Range<usize>);
+    fn next(&mut self) -> Option<Self::Item> {
+        let Spans(p, s, i) = self;
+        let (x, t) = p.run(s)?;
+        let j = *i + s.len() - t.len();
+        let r = *i..j;
+        *s = t;
+        *i = j;
+        Some((x, r))
+    }
+}
 pub struct Collect<P: Parser>(P);
 impl<P: Parser> Parser for Collect<P> {
     type Item = Vec<P::Item>;
@@ -176,6 +234,60 @@ impl<P: Parser, T, F: Fn(P::Item) -> Option<T>> Parser for FilterMap<P, T, F> {
     }
 }
 
+pub struct AtMost<P: Parser>(P, usize);
+impl<P: Parser> Parser for AtMost<P> {
+    type Item = Vec<P::Item>;
+    fn run<'a>(&self, mut s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
+        let mut v = Vec::new();
+        while v.len() < self.1 {
+            let Some((x, t)) = self.0.run(s) else { break };
+            v.push(x);
+            s = t;
+        }
+        Some((v, s))
+    }
+}
+
+pub struct TryZipWith<P, Q, F>(P, Q, F);
+impl<P, Q, T, U, V, K, F> Parser for TryZipWith<P, Q, F>
+where
+    P: Parser<Item = Result<T, Failure<K>>>,
+    Q: Parser<Item = Result<U, Failure<K>>>,
+    F: Fn(T, U) -> Result<V, Failure<K>>,
+{
+    type Item = Result<V, Failure<K>>;
+    fn run<'a>(&self, s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
+        let (x, t) = match self.0.run(s)? {
+            (Ok(x), t) => (x, t),
+            (Err(e), t) => return Some((Err(e), t)),
+        };
+        let (y, u) = match self.1.run(t)? {
+            (Ok(y), u) => (y, u),
+            (Err(e), u) => return Some((Err(e), u)),
+        };
+        Some((self.2(x, y), u))
+    }
+}
+
+pub struct TryCollect<P>(P);
+impl<P, T, K> Parser for TryCollect<P>
+where
+    P: Parser<Item = Result<T, Failure<K>>>,
+{
+    type Item = Result<Vec<T>, Failure<K>>;
+    fn run<'a>(&self, mut s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
+        let mut v = Vec::new();
+        while let Some((x, t)) = self.0.run(s) {
+            match x {
+                Ok(x) => v.push(x),
+                Err(e) => return Some((Err(e), t)),
+            }
+            s = t;
+        }
+        Some((Ok(v), s))
+    }
+}
+
 pub struct ParserChar;
 impl Parser for ParserChar {
     type Item = u8;
@@ -193,6 +305,29 @@ impl Parser for ParserEmpty {
     }
 }
 
+// Yields the length of the input left, consuming nothing
+pub struct ParserLeft;
+impl Parser for ParserLeft {
+    type Item = usize;
+    fn run<'a>(&self, s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
+        Some((s.len(), s))
+    }
+}
+
+pub struct Lookahead<P: Parser>(P);
+impl<P: Parser> Parser for Lookahead<P> {
+    type Item = P::Item;
+    fn run<'a>(&self, s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
+        let (x, _) = self.0.run(s)?;
+        Some((x, s))
+    }
+}
+
+// Runs `p` without consuming what it parsed
+pub fn lookahead<P: Parser>(p: P) -> Lookahead<P> {
+    Lookahead(p)
+}
+
 pub fn satisfy(a: u8) -> impl Parser<Item = ()> {
     ParserChar.filter_map(move |x| if x == a { Some(()) } else { None })
 }
@@ -221,6 +356,13 @@ pub fn intersperse<P: Parser>(
 mod tests {
     use super::*;
 
+    #[test]
+    fn combinator_spans() {
+        let s = r"a,b".as_bytes();
+        let v: Vec<_> = ParserChar.spans(s).collect();
+        assert_eq!(v, vec![(b'a', 0..1), (b',', 1..2), (b'b', 2..3)]);
+    }
+
     #[test]
     fn combinator_between_1() {
         let s = r"[a]".as_bytes();
@@ -242,6 +384,26 @@ mod tests {
         assert_eq!(p.run(s), Some((vec![b'a'], &[] as &[u8])));
     }
 
+    #[test]
+    fn combinator_at_most() {
+        let s = r"aaab".as_bytes();
+        let p = satisfy(b'a').at_most(2);
+        assert_eq!(p.run(s), Some((vec![(), ()], &s[2..])));
+        assert_eq!(lookahead(p).run(s), Some((vec![(), ()], s)));
+    }
+
+    #[test]
+    fn combinator_try_collect() {
+        let s = r"ab!c".as_bytes();
+        let p = ParserLeft.zip_with(ParserChar, |left, x| match x {
+            b'!' => Err(Failure { left, kind: () }),
+            _ => Ok(x),
+        });
+        let q = p.try_collect();
+        assert_eq!(q.run(&s[..2]), Some((Ok(vec![b'a', b'b']), &[] as &[u8])));
+        assert_eq!(q.run(s).unwrap().0, Err(Failure { left: 2, kind: () }));
+    }
+
     #[test]
     fn combinator_intersperse_2() {
         let s = r"a,b,c".as_bytes();
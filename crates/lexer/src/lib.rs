@@ -1,62 +1,1274 @@
-use automata::{dfa::DFA, nfa::NFABuilder, Category, ParserAutomaton};
-use combinator::Parser;
+use automata::{
+    capture::CaptureNFA,
+    codegen,
+    dfa::DFA,
+    encode::{Reader, Writer},
+    lazy::{LazyDFA, LazyState},
+    nfa::NFABuilder,
+    Category, ParserAutomaton, Partial, IR,
+};
+use regex::{Definitions, Syntax};
+use std::{
+    borrow::Cow,
+    fmt::{self, Display, Formatter},
+    io::{self, Read},
+    ops::Range,
+};
 
 mod automata;
 pub mod combinator;
 mod regex;
+mod search;
+mod span;
+mod stream;
 
+pub use search::{Matches, Regex};
+pub use span::{Lexeme, LineIndex, Position};
+pub use stream::Stream;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegexErrorKind {
+    UnexpectedEnd,
+    UnexpectedByte(u8),
+    NothingToRepeat,
+    RepeatedQuantifier,
+    UnclosedGroup,
+    UnopenedGroup,
+    UnclosedClass,
+    EmptyClass,
+    DanglingRange,
+    InvalidRange,
+    InvalidEscape(u8),
+    InvalidHexEscape,
+    OutOfRange,
+    UnknownPosixClass,
+    InvalidCount,
+    CountTooLarge,
+    EmptyAlternative,
+    InvalidName,
+    UndefinedName(String),
+    RecursiveName(String),
+}
+
+impl Display for RegexErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use RegexErrorKind::*;
+        match self {
+            UnexpectedEnd => write!(f, "unexpected end of pattern"),
+            UnexpectedByte(x) => {
+                write!(f, "unexpected `{}`", x.escape_ascii())
+            }
+            NothingToRepeat => write!(f, "quantifier with nothing to repeat"),
+            RepeatedQuantifier => write!(f, "quantifier after a quantifier"),
+            UnclosedGroup => write!(f, "unclosed `(`"),
+            UnopenedGroup => write!(f, "unmatched `)`"),
+            UnclosedClass => write!(f, "unclosed `[`"),
+            EmptyClass => write!(f, "empty class"),
+            DanglingRange => write!(f, "dangling `-`"),
+            InvalidRange => write!(f, "range out of order"),
+            InvalidEscape(x) => {
+                write!(f, "unknown escape `\\{}`", x.escape_ascii())
+            }
+            InvalidHexEscape => write!(f, "malformed `\\x` escape"),
+            OutOfRange => write!(f, "escape value out of range"),
+            UnknownPosixClass => write!(f, "unknown POSIX class"),
+            InvalidCount => write!(f, "malformed `{{m,n}}`"),
+            CountTooLarge => write!(f, "count in `{{m,n}}` too large"),
+            EmptyAlternative => write!(f, "empty alternative"),
+            InvalidName => write!(f, "malformed `{{NAME}}`"),
+            UndefinedName(x) => write!(f, "undefined name `{}`", x),
+            RecursiveName(x) => write!(f, "recursive name `{}`", x),
+        }
+    }
+}
+
+/// The pattern an error was found in: a rule by its index, in the order
+/// given to [`LexerBuilder`], or a named definition.
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub struct ParseRegexError(pub String);
+pub enum Pattern {
+    Rule(usize),
+    Definition(String),
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Rule(i) => write!(f, "rule {}", i),
+            Pattern::Definition(x) => write!(f, "definition `{}`", x),
+        }
+    }
+}
+
+/// `offset` is the byte offset into the failing pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseRegexError {
+    pub pattern: Pattern,
+    pub offset: usize,
+    pub kind: RegexErrorKind,
+}
+
+impl Display for ParseRegexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} at byte {}", self.pattern, self.kind, self.offset)
+    }
+}
+
+impl std::error::Error for ParseRegexError {}
+
+/// Why [`LexerBuilder::build`] failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    Regex(ParseRegexError),
+    /// A rule switches to a mode that [`LexerBuilder::mode`] never named.
+    UndefinedMode {
+        rule: usize,
+        name: String,
+    },
+    /// The named method, which applies to the last rule, was called
+    /// before any rule.
+    NoRule(&'static str),
+    /// [`LexerBuilder::rule_regex`] was asked for a rule it does not have.
+    UnknownRule(usize),
+}
 
-pub enum Action<T, E> {
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Regex(e) => write!(f, "{}", e),
+            BuildError::UndefinedMode { rule, name } => {
+                write!(f, "rule {}: undefined mode `{}`", rule, name)
+            }
+            BuildError::NoRule(x) => write!(f, "`{}` before any rule", x),
+            BuildError::UnknownRule(i) => write!(f, "no rule {}", i),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<ParseRegexError> for BuildError {
+    fn from(e: ParseRegexError) -> Self {
+        BuildError::Regex(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Corrupt,
+    RuleCount { expected: usize, found: usize },
+    MissingCaptures(usize),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use LoadError::*;
+        match self {
+            BadMagic => write!(f, "not a serialized lexer"),
+            UnsupportedVersion(x) => write!(f, "unsupported version {}", x),
+            Truncated => write!(f, "unexpected end of data"),
+            Corrupt => write!(f, "corrupt data"),
+            RuleCount { expected, found } => {
+                write!(f, "expected {} actions, found {}", expected, found)
+            }
+            MissingCaptures(i) => {
+                write!(f, "rule {} was not compiled with captures", i)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// A rule that can never produce a token: rules are tried in order, so on
+/// every input it matches, one of the earlier rules in `by` wins instead.
+/// `by` is empty if the rule matches nothing at all, which includes input
+/// the scan never reaches because of a [`Policy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleWarning {
+    pub rule: usize,
+    pub by: Vec<usize>,
+}
+
+// A lexer with the warnings found building it
+type Warned<T, E, Ctx> = (Lexer<T, E, Ctx>, Vec<RuleWarning>);
+
+impl Display for RuleWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.by.as_slice() {
+            [] => write!(f, "rule {} matches nothing", self.rule),
+            v => {
+                let v: Vec<String> = v.iter().map(|i| i.to_string()).collect();
+                write!(f, "rule {} is shadowed by {}", self.rule, v.join(", "))
+            }
+        }
+    }
+}
+
+const MAGIC: &[u8; 4] = b"sccl";
+const VERSION: u16 = 2;
+
+/// Submatches of a lexeme. Group 0 is the whole lexeme, the others are
+/// numbered by their `(` from left to right; `(?:...)` does not capture.
+/// Groups inside `&` and `~` operands and named definitions never match.
+pub struct Captures<'a> {
+    s: &'a [u8],
+    slots: Vec<Option<usize>>,
+}
+
+impl<'a> Captures<'a> {
+    pub fn range(&self, i: usize) -> Option<Range<usize>> {
+        match self.slots.get(2 * i..2 * i + 2)? {
+            [Some(a), Some(b)] => Some(*a..*b),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, i: usize) -> Option<&'a [u8]> {
+        self.range(i).map(|r| &self.s[r])
+    }
+}
+
+/// The tokens of a lexer, as a family of types: `Of<'a>` is the type of
+/// the tokens of input that lives for `'a`, which may borrow from it, see
+/// [`Action::B`]. A lexer names the family by its `'static` member, such
+/// as `&'static str` for `&'a str`. A type that borrows nothing is its own
+/// member for every `'a`; token types of your own need an impl like that.
+pub trait Token {
+    type Of<'a>;
+
+    /// The token as one of input that lives for `'a`, which it does not
+    /// borrow from.
+    fn shorten<'a>(self) -> Self::Of<'a>;
+}
+
+// Types that borrow nothing are tokens of any input
+macro_rules! owned_token {
+    ($($t:ty),*) => {
+        $(impl $crate::Token for $t {
+            type Of<'a> = $t;
+
+            fn shorten<'a>(self) -> Self::Of<'a> {
+                self
+            }
+        })*
+    };
+}
+
+owned_token!((), bool, char, String, Vec<u8>);
+owned_token!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl Token for &'static str {
+    type Of<'a> = &'a str;
+
+    fn shorten<'a>(self) -> &'a str {
+        self
+    }
+}
+
+impl Token for &'static [u8] {
+    type Of<'a> = &'a [u8];
+
+    fn shorten<'a>(self) -> &'a [u8] {
+        self
+    }
+}
+
+impl<T: Token> Token for Option<T> {
+    type Of<'a> = Option<T::Of<'a>>;
+
+    fn shorten<'a>(self) -> Option<T::Of<'a>> {
+        self.map(T::shorten)
+    }
+}
+
+impl<T: Token, U: Token> Token for (T, U) {
+    type Of<'a> = (T::Of<'a>, U::Of<'a>);
+
+    fn shorten<'a>(self) -> Self::Of<'a> {
+        (self.0.shorten(), self.1.shorten())
+    }
+}
+
+impl<T: Token, U: Token, V: Token> Token for (T, U, V) {
+    type Of<'a> = (T::Of<'a>, U::Of<'a>, V::Of<'a>);
+
+    fn shorten<'a>(self) -> Self::Of<'a> {
+        (self.0.shorten(), self.1.shorten(), self.2.shorten())
+    }
+}
+
+/// `G` receives the capture groups of the lexeme, found by a second pass
+/// over it, so only rules with a `G` action pay for them. `H` is a closure
+/// that also receives the context the lexer is driven with, as in
+/// [`Lexer::run_with`], to keep state across tokens. `B` receives the
+/// lexeme as part of the input, so its token, of type [`Token::Of`], can
+/// borrow it instead of copying; a [`Stream`] has no such input to lend
+/// and fails with [`LexError::Borrow`] instead. `B` is boxed as a function
+/// pointer could not take a plain `fn` returning a borrowed token.
+pub enum Action<T: Token, E, Ctx = ()> {
     C(T),
     F(fn(&[u8]) -> Result<T, E>),
+    G(fn(&Captures) -> Result<T, E>),
+    H(Closure<T, E, Ctx>),
+    B(Borrowing<T, E>),
 }
 
-pub struct Lexer<T, E> {
-    parser: ParserAutomaton<DFA>,
-    actions: Vec<Action<T, E>>,
+// A token at the start of the input, and the rest of the input
+type Run<'a, T, E> = Option<(Result<<T as Token>::Of<'a>, E>, &'a [u8])>;
+
+type Borrowing<T, E> = Box<
+    dyn for<'a> Fn(&'a [u8]) -> Result<<T as Token>::Of<'a>, E> + Send + Sync,
+>;
+
+// Shareable between threads, like the rest of a lexer
+type Closure<T, E, Ctx> =
+    Box<dyn Fn(&mut Ctx, &[u8]) -> Result<T, E> + Send + Sync>;
+
+/// How far the matches of a rule run. The automaton keeps track of it, so
+/// a match is still found in one pass over the input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Maximal munch: the longest match of any rule wins.
+    #[default]
+    Longest,
+    /// Only the shortest match of the rule counts, as if its pattern
+    /// stopped at its first accept. Other rules may still match longer.
+    Shortest,
+    /// Scanning stops as soon as the rule wins, even if another rule could
+    /// match longer.
+    FirstAccept,
 }
 
-impl<T, E> Lexer<T, E> {
+// There is one per mode, so the size of the larger variant hardly
+// matters and neither needs a box
+#[allow(clippy::large_enum_variant)]
+enum Engine {
+    Eager(ParserAutomaton<DFA>),
+    Lazy(LazyDFA),
+}
+
+// A scan of a mode that ran out of input, to go on from with more
+pub(crate) enum Resume {
+    Eager(Partial<usize>),
+    Lazy(Partial<LazyState>),
+}
+
+// How far `Lexer::step` got before its input ran out, to go on from there
+#[derive(Default)]
+pub(crate) struct Progress {
+    // Bytes at the start that no token starts at
+    skipped: usize,
+    // The scan from there, if it ran out
+    scan: Option<Resume>,
+}
+
+// What a rule does to the mode stack after it matches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ModeChange<M> {
+    Begin(M),
+    Push(M),
+    Pop,
+}
+
+// A start condition: its own automaton over a subset of the rules
+struct Mode {
+    name: String,
+    engine: Engine,
+    // The rule of each category
+    rules: Vec<usize>,
+}
+
+const INITIAL: &str = "INITIAL";
+
+/// Rules are grouped into modes, flex's start conditions, each with its
+/// own automaton; only the rules of the current mode are tried. A rule can
+/// switch modes after it matches. Each run of [`Lexer::tokens`],
+/// [`Lexer::items`] or [`Lexer::stream`] keeps its own mode stack,
+/// starting in `INITIAL`, while [`Lexer::run`], which lexes one token,
+/// always lexes in `INITIAL`.
+pub struct Lexer<T: Token, E, Ctx = ()> {
+    modes: Vec<Mode>,
+    actions: Vec<Action<T, E, Ctx>>,
+    captures: Vec<Option<CaptureNFA>>,
+    changes: Vec<Option<ModeChange<usize>>>,
+}
+
+// The mode stack of a run, never empty, the current mode on top
+pub(crate) struct Modes(Vec<usize>);
+
+impl Modes {
+    pub(crate) fn new() -> Self {
+        Modes(vec![0])
+    }
+
+    pub(crate) fn current(&self) -> usize {
+        self.0[self.0.len() - 1]
+    }
+
+    fn change(&mut self, change: Option<ModeChange<usize>>) {
+        match change {
+            Some(ModeChange::Begin(m)) => *self.0.last_mut().unwrap() = m,
+            Some(ModeChange::Push(m)) => self.0.push(m),
+            Some(ModeChange::Pop) if self.0.len() > 1 => {
+                self.0.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<T: Token, E, Ctx> Lexer<T, E, Ctx> {
     pub fn new<'a>(
-        iter: impl Iterator<Item = (&'a str, Action<T, E>)>,
-    ) -> Result<Self, ParseRegexError> {
-        let mut builder = NFABuilder::new();
+        iter: impl Iterator<Item = (&'a str, Action<T, E, Ctx>)>,
+    ) -> Result<Self, BuildError> {
+        LexerBuilder::new().rules(iter).build()
+    }
+
+    /// Like [`Lexer::new`], with the [`Policy`] of each rule.
+    pub fn with_policies<'a>(
+        iter: impl Iterator<Item = (&'a str, Action<T, E, Ctx>, Policy)>,
+    ) -> Result<Self, BuildError> {
+        let b = LexerBuilder::new();
+        iter.fold(b, |b, (r, x, p)| b.rule(r, x).policy(p)).build()
+    }
+
+    /// Like [`Lexer::new`], also reporting the rules that never win.
+    pub fn with_warnings<'a>(
+        iter: impl Iterator<Item = (&'a str, Action<T, E, Ctx>)>,
+    ) -> Result<(Self, Vec<RuleWarning>), BuildError> {
+        LexerBuilder::new().rules(iter).build_with_warnings()
+    }
+
+    pub(crate) fn mode_name(&self, modes: &Modes) -> &str {
+        &self.modes[modes.current()].name
+    }
+
+    // See `LexerBuilder::lazy`
+    fn dfa(&self, mode: usize) -> Cow<'_, DFA> {
+        match &self.modes[mode].engine {
+            Engine::Eager(p) => Cow::Borrowed(&p.0),
+            Engine::Lazy(p) => Cow::Owned(DFA::new(p.nfa())),
+        }
+    }
+
+    /// Rust source of a module with a table-driven scanner for the rules,
+    /// with no dependencies. Its `longest_match(s)` returns the rule and
+    /// length of the match [`Lexer::run`] would make there. It only emits
+    /// this matcher, not the actions or capture groups; to compile a whole
+    /// lexer ahead of time, see [`Lexer::write_to_out_dir`]. `None` if the
+    /// lexer has more than one mode, since the scanner cannot change modes.
+    pub fn to_rust(&self) -> Option<String> {
+        let [mode] = self.modes.as_slice() else {
+            return None;
+        };
+        Some(codegen::rust(&self.dfa(0), &mode.rules))
+    }
+
+    /// Graphviz DOT source of the DFA of each mode, a digraph named after
+    /// the mode, with edges labeled by the bytes they take and accepting
+    /// states by the index of their rule within the mode.
+    pub fn to_dot(&self) -> String {
+        let it = self.modes.iter().enumerate();
+        it.map(|(i, mode)| self.dfa(i).to_dot(&mode.name)).collect()
+    }
+
+    /// Encodes the compiled automata in a stable, versioned format that
+    /// [`Lexer::from_bytes`] reads back. Actions are not included. See
+    /// [`LexerBuilder::lazy`] for lazy lexers.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        w.u32(self.actions.len());
+        w.u32(self.modes.len());
+        for (i, mode) in self.modes.iter().enumerate() {
+            w.u32(mode.name.len());
+            w.bytes(mode.name.as_bytes());
+            w.u32(mode.rules.len());
+            for &rule in &mode.rules {
+                w.u32(rule);
+            }
+            self.dfa(i).write(&mut w);
+        }
+        for (c, change) in self.captures.iter().zip(&self.changes) {
+            match c {
+                Some(c) => {
+                    w.u8(1);
+                    c.write(&mut w);
+                }
+                None => w.u8(0),
+            }
+            match change {
+                None => w.u8(0),
+                Some(ModeChange::Begin(m)) => {
+                    w.u8(1);
+                    w.u32(*m);
+                }
+                Some(ModeChange::Push(m)) => {
+                    w.u8(2);
+                    w.u32(*m);
+                }
+                Some(ModeChange::Pop) => w.u8(3),
+            }
+        }
+        w.0
+    }
+
+    /// For build scripts: writes [`Lexer::to_bytes`] to the file `name` in
+    /// `OUT_DIR`, for the crate being built to load with
+    /// [`Lexer::from_bytes`] from
+    /// `include_bytes!(concat!(env!("OUT_DIR"), "/name"))`, so that the
+    /// rules are compiled once, when the crate is.
+    pub fn write_to_out_dir(&self, name: &str) -> io::Result<()> {
+        let dir = std::env::var_os("OUT_DIR").ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "OUT_DIR is not set")
+        })?;
+        std::fs::write(std::path::Path::new(&dir).join(name), self.to_bytes())
+    }
+
+    /// Loads a lexer written by [`Lexer::to_bytes`] without compiling it.
+    /// `actions` must have one action per rule, in the original order, and
+    /// a rule can only take [`Action::G`] if it had one when written.
+    pub fn from_bytes(
+        bytes: &[u8],
+        actions: impl IntoIterator<Item = Action<T, E, Ctx>>,
+    ) -> Result<Self, LoadError> {
+        let actions: Vec<_> = actions.into_iter().collect();
+        let mut r = Reader(bytes);
+        if r.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(LoadError::BadMagic);
+        }
+        match r.u16()? {
+            VERSION => {}
+            x => return Err(LoadError::UnsupportedVersion(x)),
+        }
+        let rules = r.u32()?;
+        if rules != actions.len() {
+            return Err(LoadError::RuleCount {
+                expected: rules,
+                found: actions.len(),
+            });
+        }
+        let n = r.u32()?;
+        if n == 0 {
+            return Err(LoadError::Corrupt);
+        }
+        let mut modes = Vec::new();
+        for _ in 0..n {
+            let k = r.u32()?;
+            let name = String::from_utf8(r.bytes(k)?.to_vec())
+                .map_err(|_| LoadError::Corrupt)?;
+            let k = r.u32()?;
+            let v = (0..k).map(|_| r.index(rules));
+            let mode_rules = v.collect::<Result<Vec<_>, _>>()?;
+            let dfa = DFA::read(&mut r, mode_rules.len())?;
+            modes.push(Mode {
+                name,
+                engine: Engine::Eager(ParserAutomaton(dfa)),
+                rules: mode_rules,
+            });
+        }
+        let mut captures = Vec::new();
+        let mut changes = Vec::new();
+        for (i, action) in actions.iter().enumerate() {
+            let c = match r.u8()? {
+                0 => None,
+                1 => Some(CaptureNFA::read(&mut r)?),
+                _ => return Err(LoadError::Corrupt),
+            };
+            if c.is_none() && matches!(action, Action::G(_)) {
+                return Err(LoadError::MissingCaptures(i));
+            }
+            captures.push(c);
+            changes.push(match r.u8()? {
+                0 => None,
+                1 => Some(ModeChange::Begin(r.index(n)?)),
+                2 => Some(ModeChange::Push(r.index(n)?)),
+                3 => Some(ModeChange::Pop),
+                _ => return Err(LoadError::Corrupt),
+            });
+        }
+        if !r.0.is_empty() {
+            return Err(LoadError::Corrupt);
+        }
+        Ok(Lexer {
+            modes,
+            actions,
+            captures,
+            changes,
+        })
+    }
+}
+
+struct Rule<'a, T: Token, E, Ctx> {
+    regex: &'a str,
+    action: Action<T, E, Ctx>,
+    mode: usize,
+    change: Option<ModeChange<&'a str>>,
+    policy: Policy,
+}
+
+/// Rules are tried in order; on a tie in match length the earlier rule wins.
+pub struct LexerBuilder<'a, T: Token, E, Ctx = ()> {
+    definitions: Vec<(&'a str, &'a str)>,
+    modes: Vec<&'a str>,
+    // The mode new rules go to
+    mode: usize,
+    rules: Vec<Rule<'a, T, E, Ctx>>,
+    syntax: Syntax,
+    lazy: Option<usize>,
+    // The first misuse, reported by `build`
+    error: Option<BuildError>,
+}
+
+impl<'a, T: Token, E, Ctx> Default for LexerBuilder<'a, T, E, Ctx> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, T: Token, E, Ctx> LexerBuilder<'a, T, E, Ctx> {
+    pub fn new() -> Self {
+        Self {
+            definitions: Vec::new(),
+            modes: vec![INITIAL],
+            mode: 0,
+            rules: Vec::new(),
+            syntax: Syntax::default(),
+            lazy: None,
+            error: None,
+        }
+    }
+
+    /// In Unicode mode patterns are read as codepoints and match their UTF-8
+    /// encoding, so `.` and `[^...]` consume whole characters.
+    pub fn unicode(mut self, unicode: bool) -> Self {
+        self.syntax.unicode = unicode;
+        self
+    }
+
+    /// Reads `&` and `~` as operators: `a&b` matches what both `a` and `b`
+    /// match, and `~a` what `a` does not. `&` binds looser than
+    /// concatenation and `~` looser than quantifiers. In Unicode mode `~a`
+    /// only matches valid UTF-8. Off by default, when both are plain
+    /// characters.
+    pub fn boolean(mut self, boolean: bool) -> Self {
+        self.syntax.boolean = boolean;
+        self
+    }
+
+    /// Builds DFA states as the input reaches them, caching at most
+    /// `capacity` of them, instead of the whole DFA up front. For rule sets
+    /// whose DFA is too large or slow to build eagerly. Threads lexing at
+    /// the same time each get a cache of their own. What needs the whole
+    /// DFA, like [`Lexer::to_bytes`] or
+    /// [`LexerBuilder::build_with_warnings`], still determinizes a lazy
+    /// lexer in full.
+    pub fn lazy(mut self, capacity: usize) -> Self {
+        self.lazy = Some(capacity);
+        self
+    }
+
+    /// Names a sub-pattern that rules and other definitions can refer to
+    /// as `{NAME}`. References are resolved after parsing, so definitions
+    /// may come in any order but must not refer to themselves.
+    pub fn define(mut self, name: &'a str, regex: &'a str) -> Self {
+        self.definitions.push((name, regex));
+        self
+    }
+
+    /// Rules added from here on belong to the mode `name`, until the next
+    /// call. Before the first call they go to `INITIAL`, the mode a lexer
+    /// starts in.
+    pub fn mode(mut self, name: &'a str) -> Self {
+        self.mode = match self.modes.iter().position(|&m| m == name) {
+            Some(i) => i,
+            None => {
+                self.modes.push(name);
+                self.modes.len() - 1
+            }
+        };
+        self
+    }
+
+    pub fn rule(mut self, regex: &'a str, action: Action<T, E, Ctx>) -> Self {
+        self.rules.push(Rule {
+            regex,
+            action,
+            mode: self.mode,
+            change: None,
+            policy: Policy::Longest,
+        });
+        self
+    }
+
+    pub fn rules(
+        self,
+        iter: impl IntoIterator<Item = (&'a str, Action<T, E, Ctx>)>,
+    ) -> Self {
+        iter.into_iter().fold(self, |b, (r, x)| b.rule(r, x))
+    }
+
+    // The last rule, or None after recording that `method` needs one
+    fn last_rule(
+        &mut self,
+        method: &'static str,
+    ) -> Option<&mut Rule<'a, T, E, Ctx>> {
+        if self.rules.is_empty() {
+            self.error.get_or_insert(BuildError::NoRule(method));
+        }
+        self.rules.last_mut()
+    }
+
+    fn change(mut self, method: &'static str, x: ModeChange<&'a str>) -> Self {
+        if let Some(rule) = self.last_rule(method) {
+            rule.change = Some(x);
+        }
+        self
+    }
+
+    /// Sets the [`Policy`] of the last rule.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        if let Some(rule) = self.last_rule("policy") {
+            rule.policy = policy;
+        }
+        self
+    }
+
+    /// After the last rule matches, the lexer switches to the mode `name`.
+    pub fn begin(self, name: &'a str) -> Self {
+        self.change("begin", ModeChange::Begin(name))
+    }
+
+    /// Like [`LexerBuilder::begin`], but saves the current mode for a
+    /// later [`LexerBuilder::pop`].
+    pub fn push(self, name: &'a str) -> Self {
+        self.change("push", ModeChange::Push(name))
+    }
+
+    /// After the last rule matches, the lexer returns to the mode saved by
+    /// the latest push, or stays put if there is none.
+    pub fn pop(self) -> Self {
+        self.change("pop", ModeChange::Pop)
+    }
+
+    fn resolve(
+        &self,
+        i: usize,
+    ) -> Result<Option<ModeChange<usize>>, BuildError> {
+        let mode = |name: &str| {
+            let m = self.modes.iter().position(|&m| m == name);
+            m.ok_or_else(|| BuildError::UndefinedMode {
+                rule: i,
+                name: name.to_string(),
+            })
+        };
+        Ok(match self.rules[i].change {
+            None => None,
+            Some(ModeChange::Begin(m)) => Some(ModeChange::Begin(mode(m)?)),
+            Some(ModeChange::Push(m)) => Some(ModeChange::Push(mode(m)?)),
+            Some(ModeChange::Pop) => Some(ModeChange::Pop),
+        })
+    }
+
+    fn irs(&self) -> Result<Vec<IR>, ParseRegexError> {
+        let mut definitions = Definitions::new(self.syntax);
+        for (name, regex) in &self.definitions {
+            definitions.define(name, regex)?;
+        }
+        self.rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| definitions.ir(rule.regex, Pattern::Rule(i)))
+            .collect()
+    }
+
+    /// Graphviz DOT source of the NFA the rules of each mode compile to,
+    /// before it is determinized, in the format of [`Lexer::to_dot`].
+    pub fn nfa_dot(&self) -> Result<String, BuildError> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+        let irs = self.irs()?;
+        let dot = |(m, name): (usize, &&str)| {
+            let builder = irs
+                .iter()
+                .zip(&self.rules)
+                .filter(|(_, rule)| rule.mode == m)
+                .fold(NFABuilder::new(), |mut b, (ir, rule)| {
+                    b.add_rule(ir, rule.policy);
+                    b
+                });
+            builder.build().to_dot(name)
+        };
+        Ok(self.modes.iter().enumerate().map(dot).collect())
+    }
+
+    /// The pattern of rule `i`, counting from 0 across all modes, with its
+    /// definitions expanded, as a [`Regex`] searching anywhere. Compare
+    /// rules as languages with it, e.g. a rewritten rule with the old one.
+    pub fn rule_regex(&self, i: usize) -> Result<Regex, BuildError> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+        if i >= self.rules.len() {
+            return Err(BuildError::UnknownRule(i));
+        }
+        let ir = self.irs()?.swap_remove(i);
+        Ok(Regex::from_ir(ir, self.syntax.unicode))
+    }
+
+    pub fn build(self) -> Result<Lexer<T, E, Ctx>, BuildError> {
+        self.compile(false).map(|(lexer, _)| lexer)
+    }
+
+    /// Also reports each rule that no input reaches as the winning rule,
+    /// with the earlier rules of its mode that take its inputs, from the
+    /// whole DFA; see [`LexerBuilder::lazy`].
+    pub fn build_with_warnings(self) -> Result<Warned<T, E, Ctx>, BuildError> {
+        self.compile(true)
+    }
+
+    fn compile(self, warn: bool) -> Result<Warned<T, E, Ctx>, BuildError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let irs = self.irs()?;
+        let mut builders: Vec<_> =
+            self.modes.iter().map(|_| NFABuilder::new()).collect();
+        let mut mode_rules = vec![Vec::new(); self.modes.len()];
+        let changes = (0..self.rules.len())
+            .map(|i| self.resolve(i))
+            .collect::<Result<Vec<_>, _>>()?;
         let mut actions = Vec::new();
-        for (regex, action) in iter {
-            let ir = regex.parse()?;
-            builder.add_ir(&ir);
-            actions.push(action);
+        let mut captures = Vec::new();
+        for (i, (ir, rule)) in irs.iter().zip(self.rules).enumerate() {
+            builders[rule.mode].add_rule(ir, rule.policy);
+            mode_rules[rule.mode].push(i);
+            captures.push(
+                matches!(rule.action, Action::G(_))
+                    .then(|| CaptureNFA::new(ir)),
+            );
+            actions.push(rule.action);
+        }
+        let mut modes = Vec::new();
+        let mut warnings = Vec::new();
+        let it = self.modes.iter().zip(builders).zip(mode_rules);
+        for ((name, builder), rules) in it {
+            let nfa = builder.build();
+            let (engine, winners) = match self.lazy {
+                Some(capacity) => {
+                    let w = if warn {
+                        DFA::with_winners(&nfa).1
+                    } else {
+                        Vec::new()
+                    };
+                    let lazy = LazyDFA::new(nfa, capacity);
+                    (Engine::Lazy(lazy), w)
+                }
+                None => {
+                    let (dfa, w) = DFA::with_winners(&nfa);
+                    (Engine::Eager(ParserAutomaton(dfa)), w)
+                }
+            };
+            warnings.extend(
+                winners
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(c, w)| warn && !w.contains(c))
+                    .map(|(c, w)| RuleWarning {
+                        rule: rules[c],
+                        by: w.into_iter().map(|j| rules[j]).collect(),
+                    }),
+            );
+            modes.push(Mode {
+                name: name.to_string(),
+                engine,
+                rules,
+            });
         }
-        let nfa = builder.build();
-        let dfa = DFA::new(&nfa);
-        let parser = ParserAutomaton(dfa);
-        Ok(Self { parser, actions })
+        warnings.sort_by_key(|w| w.rule);
+        let lexer = Lexer {
+            modes,
+            actions,
+            captures,
+            changes,
+        };
+        Ok((lexer, warnings))
     }
 }
 
-impl<T: Clone, E> Parser for Lexer<T, E> {
-    type Item = Result<T, E>;
-    fn run<'a>(&self, s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
-        use Action::*;
-        self.parser.run(s).map(|(c, t)| {
-            let Category(i) = c;
-            let offset = unsafe { t.as_ptr().offset_from(s.as_ptr()) } as usize;
-            let r = match &self.actions[i] {
-                C(x) => Ok(x.clone()),
-                F(f) => f(s.split_at(offset).0),
+impl<T: Token + Clone, E, Ctx> Lexer<T, E, Ctx> {
+    // The rule and length of the match in mode `m`
+    fn scan(&self, m: usize, s: &[u8]) -> Option<(usize, usize)> {
+        match self.resume(m, None, s) {
+            Ok(r) => r,
+            Err(p) => self.matched(m, &p),
+        }
+    }
+
+    // Scans `s` in mode `m`, going on from `p`, where a scan of a prefix
+    // of `s` in that mode ran out: the rule and length of the match once
+    // the automaton dies, or the scan when `s` runs out too
+    fn resume(
+        &self,
+        m: usize,
+        p: Option<Resume>,
+        s: &[u8],
+    ) -> Result<Option<(usize, usize)>, Resume> {
+        let mode = &self.modes[m];
+        let r = match &mode.engine {
+            Engine::Eager(a) => {
+                let p = match p {
+                    Some(Resume::Eager(p)) => p,
+                    _ => a.begin(),
+                };
+                a.resume(p, s).map_err(Resume::Eager)
+            }
+            // The cache of the run is not there to go on with later
+            Engine::Lazy(a) => {
+                let a = ParserAutomaton(a.start());
+                let p = match p {
+                    Some(Resume::Lazy(p)) => p.map(|q| a.0.attach(q)),
+                    _ => a.begin(),
+                };
+                let r = a.resume(p, s);
+                r.map_err(|p| Resume::Lazy(p.map(|q| a.0.detach(q))))
+            }
+        };
+        r.map(|r| r.map(|(Category(c), n)| (mode.rules[c], n)))
+    }
+
+    // The rule and length of the longest match of a scan of mode `m` so
+    // far
+    fn matched(&self, m: usize, p: &Resume) -> Option<(usize, usize)> {
+        let r = match p {
+            Resume::Eager(p) => p.r,
+            Resume::Lazy(p) => p.r,
+        };
+        r.map(|(Category(c), n)| (self.modes[m].rules[c], n))
+    }
+
+    // What comes next in `s`, skipping a character at a time until some
+    // rule matches again. Empty matches would make no progress, so they
+    // count as no match. Unless `s` runs to the end of the input, gives
+    // up with `Step::More` where more input could change the outcome,
+    // keeping in `progress` how far it got. Given the same `s` with more
+    // input after it, it goes on from there instead of starting over.
+    fn step(
+        &self,
+        mode: usize,
+        s: &[u8],
+        end: bool,
+        progress: &mut Progress,
+    ) -> Option<Step> {
+        let mut j = progress.skipped;
+        while j < s.len() {
+            let m = match self.resume(mode, progress.scan.take(), &s[j..]) {
+                Ok(m) => m,
+                Err(p) if !end => {
+                    progress.skipped = j;
+                    progress.scan = Some(p);
+                    return Some(Step::More);
+                }
+                Err(p) => self.matched(mode, &p),
             };
-            (r, t)
+            match m {
+                Some((rule, n)) if n > 0 && j == 0 => {
+                    return Some(Step::Token(rule, n))
+                }
+                Some((_, n)) if n > 0 => break,
+                // A character may be cut off at the end
+                _ if !end && s.len() - j < 4 => {
+                    progress.skipped = j;
+                    return Some(Step::More);
+                }
+                _ => j += char_len(&s[j..]),
+            }
+        }
+        // Either `s` is empty or the unmatched run may go on
+        if j == s.len() && !end {
+            progress.skipped = j;
+            return Some(Step::More);
+        }
+        Some(j).filter(|&j| j > 0).map(Step::Unmatched)
+    }
+
+    // Runs the action of rule `i` on its lexeme, then changes the modes of
+    // the run. See `action`.
+    fn apply(
+        &self,
+        ctx: &mut Ctx,
+        modes: &mut Modes,
+        i: usize,
+        s: &[u8],
+    ) -> Result<Result<T, E>, &Borrowing<T, E>> {
+        let r = self.action(ctx, i, s);
+        modes.change(self.changes[i]);
+        r
+    }
+
+    // Runs the action of rule `i` on its lexeme `s`, unless it borrows
+    // from the input, which `s` need not be part of: then it comes back to
+    // be run on the lexeme in the input
+    fn action(
+        &self,
+        ctx: &mut Ctx,
+        i: usize,
+        s: &[u8],
+    ) -> Result<Result<T, E>, &Borrowing<T, E>> {
+        use Action::*;
+        Ok(match &self.actions[i] {
+            C(x) => Ok(x.clone()),
+            F(f) => f(s),
+            // The DFA and the capture NFA should agree on the lexeme, but
+            // if not, the action sees no groups rather than the lexer
+            // panicking
+            G(f) => {
+                let slots = self.captures[i]
+                    .as_ref()
+                    .and_then(|nfa| nfa.captures(s))
+                    .unwrap_or_default();
+                f(&Captures { s, slots })
+            }
+            H(f) => f(ctx, s),
+            B(f) => return Err(f),
+        })
+    }
+
+    /// [`Lexer::run`], passing `ctx` to the [`Action::H`] actions.
+    pub fn run_with<'a>(&self, ctx: &mut Ctx, s: &'a [u8]) -> Run<'a, T, E> {
+        let (rule, n) = self.scan(0, s)?;
+        let (s, t) = s.split_at(n);
+        Some((lend(self.action(ctx, rule, s), s), t))
+    }
+
+    /// Lexes all of `s`, with the byte range of each token. Unlike
+    /// [`Lexer::items`], which stops where no rule matches, input that no
+    /// rule matches comes out as [`LexError::Unmatched`] and lexing goes on
+    /// after it, so no input is lost. Actions get a fresh context.
+    pub fn tokens<'a, 's>(&'a self, s: &'s [u8]) -> Tokens<'a, 's, T, E, Ctx>
+    where
+        Ctx: Default,
+    {
+        Tokens {
+            lexer: self,
+            ctx: Context::Owned(Ctx::default()),
+            modes: Modes::new(),
+            s,
+            i: 0,
+        }
+    }
+
+    /// [`Lexer::tokens`] with the context `ctx`.
+    pub fn tokens_with<'a, 's>(
+        &'a self,
+        ctx: &'a mut Ctx,
+        s: &'s [u8],
+    ) -> Tokens<'a, 's, T, E, Ctx> {
+        Tokens {
+            lexer: self,
+            ctx: Context::Borrowed(ctx),
+            modes: Modes::new(),
+            s,
+            i: 0,
+        }
+    }
+
+    /// Lexes what `reader` yields without reading it all into memory
+    /// first. See [`Stream`]. Actions get a fresh context.
+    pub fn stream<R: Read>(&self, reader: R) -> Stream<'_, R, T, E, Ctx>
+    where
+        Ctx: Default,
+    {
+        Stream::new(self, Context::Owned(Ctx::default()), reader)
+    }
+
+    /// [`Lexer::stream`] with the context `ctx`.
+    pub fn stream_with<'a, R: Read>(
+        &'a self,
+        ctx: &'a mut Ctx,
+        reader: R,
+    ) -> Stream<'a, R, T, E, Ctx> {
+        Stream::new(self, Context::Borrowed(ctx), reader)
+    }
+}
+
+// The context of a run: the caller's, or a fresh one
+enum Context<'a, Ctx> {
+    Owned(Ctx),
+    Borrowed(&'a mut Ctx),
+}
+
+impl<'a, Ctx> Context<'a, Ctx> {
+    fn get(&mut self) -> &mut Ctx {
+        match self {
+            Context::Owned(ctx) => ctx,
+            Context::Borrowed(ctx) => ctx,
+        }
+    }
+}
+
+/// Why [`Lexer::tokens`] has no token for a range of the input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LexError<E> {
+    /// The action of the matching rule failed.
+    Action(E),
+    /// No rule matches anywhere in the range.
+    Unmatched,
+    /// The matching rule has an [`Action::B`], which a [`Stream`] cannot
+    /// run.
+    Borrow,
+}
+
+impl<E: Display> Display for LexError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::Action(e) => write!(f, "{}", e),
+            LexError::Unmatched => write!(f, "no rule matches"),
+            LexError::Borrow => write!(f, "borrowing action in a stream"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + Display> std::error::Error for LexError<E> {}
+
+// The token of a lexeme `s` of the input from what `Lexer::action` gave,
+// running a borrowing action on `s`
+fn lend<'a, 'b, T: Token + 'b, E: 'b>(
+    r: Result<Result<T, E>, &'b Borrowing<T, E>>,
+    s: &'a [u8],
+) -> Result<T::Of<'a>, E> {
+    match r {
+        Ok(r) => r.map(T::shorten),
+        Err(f) => f(s),
+    }
+}
+
+enum Step {
+    // A rule and the length of its lexeme
+    Token(usize, usize),
+    // Bytes no rule matches
+    Unmatched(usize),
+    More,
+}
+
+// Length of the character at the start of `s`, or 1 if it is not UTF-8
+fn char_len(s: &[u8]) -> usize {
+    let n = match s[0] {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => 1,
+    };
+    match s.get(..n).map(std::str::from_utf8) {
+        Some(Ok(_)) => n,
+        _ => 1,
+    }
+}
+
+pub struct Tokens<'a, 's, T: Token, E, Ctx = ()> {
+    lexer: &'a Lexer<T, E, Ctx>,
+    ctx: Context<'a, Ctx>,
+    modes: Modes,
+    s: &'s [u8],
+    i: usize,
+}
+
+impl<'a, 's, T: Token + Clone, E, Ctx> Iterator for Tokens<'a, 's, T, E, Ctx> {
+    type Item = (Result<T::Of<'s>, LexError<E>>, Range<usize>);
+
+    // A run of unmatched input comes out as one error
+    fn next(&mut self) -> Option<Self::Item> {
+        let mode = self.modes.current();
+        let progress = &mut Progress::default();
+        let (r, n) = match self.lexer.step(mode, self.s, true, progress)? {
+            Step::Token(rule, n) => {
+                let (ctx, s) = (self.ctx.get(), &self.s[..n]);
+                let r = self.lexer.apply(ctx, &mut self.modes, rule, s);
+                (lend(r, s).map_err(LexError::Action), n)
+            }
+            Step::Unmatched(n) => (Err(LexError::Unmatched), n),
+            Step::More => unreachable!("more input past the end"),
+        };
+        let span = self.i..self.i + n;
+        self.s = &self.s[n..];
+        self.i += n;
+        Some((r, span))
+    }
+}
+
+impl<'a, 's, T: Token, E, Ctx> Tokens<'a, 's, T, E, Ctx> {
+    /// The name of the mode the next token is lexed in.
+    pub fn mode(&self) -> &'a str {
+        self.lexer.mode_name(&self.modes)
+    }
+
+    /// Gives each token its text along with its span, borrowed from the
+    /// input, so an action can classify a token and leave its text alone.
+    pub fn lexemes(self) -> Lexemes<'a, 's, T, E, Ctx> {
+        Lexemes(self)
+    }
+}
+
+pub struct Lexemes<'a, 's, T: Token, E, Ctx = ()>(Tokens<'a, 's, T, E, Ctx>);
+
+impl<'a, 's, T: Token, E, Ctx> Lexemes<'a, 's, T, E, Ctx> {
+    /// See [`Tokens::mode`].
+    pub fn mode(&self) -> &'a str {
+        self.0.mode()
+    }
+}
+
+impl<'a, 's, T: Token + Clone, E, Ctx> Iterator for Lexemes<'a, 's, T, E, Ctx> {
+    type Item = (Result<T::Of<'s>, LexError<E>>, Lexeme<'s>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.0.s;
+        let (r, span) = self.0.next()?;
+        let text = &s[..span.len()];
+        Some((r, Lexeme { text, span }))
+    }
+}
+
+// Not a `Parser`, whose items cannot borrow from the input
+impl<T: Token + Clone, E> Lexer<T, E> {
+    /// The token at the start of `s`, lexed in the initial mode, and the
+    /// rest of `s`, as a [`Parser`] would give them.
+    ///
+    /// [`Parser`]: combinator::Parser
+    pub fn run<'a>(&self, s: &'a [u8]) -> Run<'a, T, E> {
+        self.run_with(&mut (), s)
+    }
+
+    /// The tokens of `s` up to where no rule matches, or only matches
+    /// the empty string, switching modes as the rules say.
+    pub fn items<'a>(
+        &self,
+        mut s: &'a [u8],
+    ) -> impl Iterator<Item = Result<T::Of<'a>, E>> + use<'_, 'a, T, E> {
+        let mut modes = Modes::new();
+        std::iter::from_fn(move || {
+            let (rule, n) = self.scan(modes.current(), s)?;
+            let (x, t) = s.split_at(n);
+            s = t;
+            (n > 0).then(|| lend(self.apply(&mut (), &mut modes, rule, x), x))
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::str::from_utf8;
+    use std::{collections::HashSet, str::from_utf8};
 
-    use crate::{combinator::Parser, Action, Lexer};
+    use crate::{
+        Action, BuildError, Captures, LexError, Lexeme, Lexer, LexerBuilder,
+        LoadError, ParseRegexError, Pattern, Policy, RegexErrorKind,
+        RuleWarning,
+    };
 
     fn from_bytes(s: &[u8]) -> Result<isize, ()> {
         Ok(from_utf8(s).unwrap().parse().unwrap())
@@ -88,4 +1300,663 @@ mod tests {
         assert_eq!(lexer.run("-123a".as_bytes()).unwrap().1[0], b'a');
         assert_eq!(lexer.run("0456a".as_bytes()).unwrap().1[0], b'a');
     }
+
+    #[test]
+    fn lex_error() {
+        let v: Vec<(&str, Action<usize, ()>)> = vec![
+            (r"a", Action::C(0)),
+            (r"b", Action::C(1)),
+            (r"[cd-]", Action::C(2)),
+        ];
+        let e = Lexer::new(v.into_iter()).err().unwrap();
+        assert_eq!(
+            e,
+            BuildError::Regex(ParseRegexError {
+                pattern: Pattern::Rule(2),
+                offset: 3,
+                kind: RegexErrorKind::DanglingRange
+            })
+        );
+        assert_eq!(e.to_string(), "rule 2: dangling `-` at byte 3");
+    }
+
+    #[test]
+    fn lex_unicode() {
+        let lexer = LexerBuilder::<usize, ()>::new()
+            .unicode(true)
+            .rule(r"[a-zα-ω]+", Action::C(0))
+            .rule(r"\s+", Action::C(1))
+            .rule(r".", Action::C(2))
+            .build()
+            .unwrap();
+        let v: Vec<usize> = lexer
+            .items("λx ab€".as_bytes())
+            .collect::<Result<_, ()>>()
+            .unwrap();
+        assert_eq!(v, vec![0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn lex_longest_accepted_prefix() {
+        let v: Vec<(&str, Action<usize, ()>)> =
+            vec![(r"\.\.\.", Action::C(0)), (r"\.", Action::C(1))];
+        let lexer = Lexer::new(v.into_iter()).unwrap();
+        let (x, t) = lexer.run("..".as_bytes()).unwrap();
+        assert_eq!((x.unwrap(), t), (1, ".".as_bytes()));
+        let (x, t) = lexer.run("....".as_bytes()).unwrap();
+        assert_eq!((x.unwrap(), t), (0, ".".as_bytes()));
+    }
+
+    #[test]
+    fn lex_definitions() {
+        let lexer = LexerBuilder::<usize, ()>::new()
+            .define("E", r"[eE][\+\-]?{D}+")
+            .define("D", r"[0-9]")
+            .rule(r"{D}+\.{D}*{E}?|{D}+{E}", Action::C(0))
+            .rule(r"{D}+", Action::C(1))
+            .build()
+            .unwrap();
+        let run = |s: &'static str| {
+            lexer.run(s.as_bytes()).map(|(x, t)| (x, t.len()))
+        };
+        assert_eq!(run("12"), Some((Ok(1), 0)));
+        assert_eq!(run("12.5e-3"), Some((Ok(0), 0)));
+        assert_eq!(run("12e3"), Some((Ok(0), 0)));
+        assert_eq!(run("12e"), Some((Ok(1), 1)));
+        assert_eq!(run("{D}"), None);
+    }
+
+    #[test]
+    fn lex_captures() {
+        fn float(c: &Captures) -> Result<(String, String, String), ()> {
+            let s = |i| from_utf8(c.get(i).unwrap_or(b"")).unwrap().to_owned();
+            Ok((s(1), s(2), s(3)))
+        }
+        let lexer = LexerBuilder::new()
+            .define("D", r"[0-9]")
+            .define("E", r"[eE](\+|\-)?{D}+")
+            .rule(r"({D}+\.{D}*|\.{D}+)({E})?([fFlL])?", Action::G(float))
+            .build()
+            .unwrap();
+        let run = |s: &'static str| lexer.run(s.as_bytes()).unwrap().0.unwrap();
+        let t = |a: &str, b: &str, c: &str| {
+            (a.to_owned(), b.to_owned(), c.to_owned())
+        };
+        assert_eq!(run("1.5e+10f"), t("1.5", "e+10", "f"));
+        assert_eq!(run("12."), t("12.", "", ""));
+        assert_eq!(run(".5L"), t(".5", "", "L"));
+    }
+
+    #[test]
+    fn lex_lazy() {
+        fn build(b: LexerBuilder<usize, ()>) -> Lexer<usize, ()> {
+            b.rule(r"if|else", Action::C(0))
+                .rule(r"\l\w*", Action::C(1))
+                .rule(r"\d+", Action::C(2))
+                .rule(r"\s+", Action::C(3))
+                .build()
+                .unwrap()
+        }
+        let code = "if x1 else 42 iffy ".repeat(20);
+        let eager: Vec<_> =
+            build(LexerBuilder::new()).items(code.as_bytes()).collect();
+        for capacity in [2, 4, 100] {
+            let lazy = build(LexerBuilder::new().lazy(capacity));
+            assert_eq!(lazy.items(code.as_bytes()).collect::<Vec<_>>(), eager);
+        }
+        assert_eq!(eager.len(), 200);
+    }
+
+    #[test]
+    fn lex_bytes() {
+        fn sum(c: &Captures) -> Result<isize, ()> {
+            let n = |i| from_bytes(c.get(i).unwrap());
+            Ok(n(1)? + n(2)?)
+        }
+        let actions =
+            || vec![Action::C(-1), Action::G(sum), Action::F(from_bytes)];
+        let rules = [r"\s+", r"(\d+)\+(\d+)", r"\d+"];
+        let build = |b: LexerBuilder<'static, isize, ()>| {
+            b.rules(rules.into_iter().zip(actions())).build().unwrap()
+        };
+        let code = "12 3+4 5 ".as_bytes();
+        let lexer = build(LexerBuilder::new());
+        let expected: Vec<_> = lexer.items(code).collect();
+        assert_eq!(
+            expected,
+            vec![Ok(12), Ok(-1), Ok(7), Ok(-1), Ok(5), Ok(-1)]
+        );
+        let bytes = lexer.to_bytes();
+        let loaded = Lexer::from_bytes(&bytes, actions()).unwrap();
+        assert_eq!(loaded.items(code).collect::<Vec<_>>(), expected);
+        assert_eq!(loaded.to_bytes(), bytes);
+        // A lazy lexer writes the same automaton
+        assert_eq!(build(LexerBuilder::new().lazy(2)).to_bytes(), bytes);
+
+        let load = |b: &[u8], v: Vec<Action<isize, ()>>| {
+            Lexer::from_bytes(b, v).err().unwrap()
+        };
+        assert_eq!(load(b"nope", actions()), LoadError::BadMagic);
+        let mut v = bytes.clone();
+        v[4] = 9;
+        assert_eq!(load(&v, actions()), LoadError::UnsupportedVersion(9));
+        assert_eq!(
+            load(&bytes, vec![Action::C(0)]),
+            LoadError::RuleCount {
+                expected: 3,
+                found: 1
+            }
+        );
+        let v = vec![Action::G(sum), Action::G(sum), Action::C(0)];
+        assert_eq!(load(&bytes, v), LoadError::MissingCaptures(0));
+        for n in [0, 7, bytes.len() / 2, bytes.len() - 1] {
+            assert!(Lexer::from_bytes(&bytes[..n], actions()).is_err());
+        }
+        let mut v = bytes.clone();
+        v.push(0);
+        assert_eq!(load(&v, actions()), LoadError::Corrupt);
+    }
+
+    #[test]
+    fn lex_warnings() {
+        let v: Vec<(&str, Action<usize, ()>)> = vec![
+            (r"\l\w*", Action::C(0)),
+            (r"int", Action::C(1)),
+            (r"\d+", Action::C(2)),
+            (r"0x\h+|\d+", Action::C(3)),
+            (r"a&b", Action::C(4)),
+            (r"\d+|x", Action::C(5)),
+        ];
+        let (lexer, warnings) = LexerBuilder::new()
+            .boolean(true)
+            .rules(v)
+            .build_with_warnings()
+            .unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                RuleWarning {
+                    rule: 1,
+                    by: vec![0]
+                },
+                RuleWarning {
+                    rule: 4,
+                    by: vec![]
+                },
+                RuleWarning {
+                    rule: 5,
+                    by: vec![0, 2]
+                },
+            ]
+        );
+        assert_eq!(warnings[0].to_string(), "rule 1 is shadowed by 0");
+        assert_eq!(warnings[1].to_string(), "rule 4 matches nothing");
+        assert_eq!(lexer.run("0x1f".as_bytes()).unwrap().0, Ok(3));
+        let (_, lazy) = LexerBuilder::new()
+            .rules(vec![(r"\w+", Action::<_, ()>::C(0)), (r"if", Action::C(1))])
+            .lazy(4)
+            .build_with_warnings()
+            .unwrap();
+        assert_eq!(
+            lazy,
+            vec![RuleWarning {
+                rule: 1,
+                by: vec![0]
+            }]
+        );
+    }
+
+    #[test]
+    fn lex_modes() {
+        let builder = || {
+            LexerBuilder::<&str, ()>::new()
+                .rule(r"/\*", Action::C("open"))
+                .begin("COMMENT")
+                .rule(r#"""#, Action::C("quote"))
+                .push("STR")
+                .rule(r"\w+", Action::C("word"))
+                .rule(r"\s+", Action::C("space"))
+                .mode("STR")
+                .rule(r#"[^"\\]+"#, Action::C("text"))
+                .rule(r"\\.", Action::C("escape"))
+                .rule(r#"""#, Action::C("quote"))
+                .pop()
+                .mode("COMMENT")
+                .rule(r"\*/", Action::C("close"))
+                .begin("INITIAL")
+                .rule(r"[\s\S]", Action::C("comment"))
+        };
+        let lexer = builder().build().unwrap();
+        let s = r#"a "b \"c" /*"*/d"#;
+        let words = |lexer: &Lexer<_, _>| -> Vec<_> {
+            let v = lexer.tokens(s.as_bytes());
+            v.map(|(r, _)| r.unwrap()).collect()
+        };
+        let v = words(&lexer);
+        assert_eq!(
+            v,
+            vec![
+                "word", "space", "quote", "text", "escape", "text", "quote",
+                "space", "open", "comment", "close", "word"
+            ]
+        );
+        let mut tokens = lexer.tokens(r#""x"#.as_bytes());
+        assert_eq!(tokens.mode(), "INITIAL");
+        assert_eq!(tokens.next().unwrap().0, Ok("quote"));
+        assert_eq!(tokens.mode(), "STR");
+        assert_eq!(tokens.next().unwrap().0, Ok("text"));
+        // Every run starts over in the initial mode
+        let mut stream = lexer.stream("x".as_bytes());
+        assert_eq!(stream.mode(), "INITIAL");
+        assert_eq!(stream.next().unwrap().unwrap().0, Ok("word"));
+        let items: Vec<_> = lexer.items(s.as_bytes()).collect();
+        assert_eq!(items, v.iter().map(|&x| Ok(x)).collect::<Vec<_>>());
+        assert_eq!(lexer.run(r#""x"#.as_bytes()).unwrap().0, Ok("quote"));
+        assert_eq!(lexer.run("x".as_bytes()).unwrap().0, Ok("word"));
+
+        let bytes = lexer.to_bytes();
+        let actions = builder().rules.into_iter().map(|r| r.action);
+        let loaded = Lexer::from_bytes(&bytes, actions).unwrap();
+        assert_eq!(words(&loaded), v);
+        let dot = lexer.to_dot();
+        assert_eq!(dot.matches("digraph").count(), 3);
+        assert!(dot.contains("digraph \"COMMENT\" {"));
+        assert_eq!(builder().nfa_dot().unwrap().matches("digraph").count(), 3);
+        assert_eq!(lexer.to_rust(), None);
+
+        let e = builder().rule(r"x", Action::C("x")).push("STRING").build();
+        assert_eq!(
+            e.err().unwrap(),
+            BuildError::UndefinedMode {
+                rule: 9,
+                name: "STRING".to_string()
+            }
+        );
+        let e = LexerBuilder::<(), ()>::new().pop().rule("x", Action::C(()));
+        let e = e.policy(Policy::Shortest).build().err().unwrap();
+        assert_eq!(e, BuildError::NoRule("pop"));
+        assert_eq!(e.to_string(), "`pop` before any rule");
+    }
+
+    #[test]
+    fn lex_sync() {
+        fn sync<T: Send + Sync>(_: &T) {}
+        let builder = || {
+            LexerBuilder::<usize, (), Vec<u8>>::new()
+                .rule(r"a", Action::C(0))
+                .rule(r"b", Action::H(Box::new(|_, _| Ok(1))))
+        };
+        sync(&builder().build().unwrap());
+        sync(&builder().lazy(4).build().unwrap());
+    }
+
+    #[test]
+    fn lex_lazy_threads() {
+        // Exponentially many DFA states, so a small cache keeps flushing
+        let build = |b: LexerBuilder<'static, usize, ()>| {
+            b.rule(r"[ab]*a[ab]{6}", Action::C(0))
+                .rule(r"[cd]+", Action::C(1))
+                .build()
+                .unwrap()
+        };
+        let eager = build(LexerBuilder::new());
+        let lazy = build(LexerBuilder::new().lazy(4));
+        let input = |t: usize| -> Vec<u8> {
+            let byte = |i: usize| match (i * 7 + t) % 100 {
+                0..=89 if (i * i + t * i).is_multiple_of(3) => b'a',
+                0..=89 => b'b',
+                _ => b'c',
+            };
+            (0..3000).map(byte).collect()
+        };
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let (eager, lazy) = (&eager, &lazy);
+                scope.spawn(move || {
+                    let s = input(t);
+                    let expected: Vec<_> = eager.tokens(&s).collect();
+                    for _ in 0..4 {
+                        assert_eq!(
+                            lazy.tokens(&s).collect::<Vec<_>>(),
+                            expected
+                        );
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn lex_tokens() {
+        fn word(s: &[u8]) -> Result<usize, &'static str> {
+            match s {
+                b"bad" => Err("bad word"),
+                _ => Ok(s.len()),
+            }
+        }
+        let lexer = LexerBuilder::new()
+            .rule(r"[a-z]+", Action::F(word))
+            .rule(r" ", Action::C(0))
+            .build()
+            .unwrap();
+        // `é€` are two characters, `\xFF` is not UTF-8
+        let s = b"ab @$ bad \xC3\xA9\xE2\x82\xACc\xFF";
+        let v: Vec<_> = lexer.tokens(s).collect();
+        assert_eq!(
+            v,
+            vec![
+                (Ok(2), 0..2),
+                (Ok(0), 2..3),
+                (Err(LexError::Unmatched), 3..5),
+                (Ok(0), 5..6),
+                (Err(LexError::Action("bad word")), 6..9),
+                (Ok(0), 9..10),
+                (Err(LexError::Unmatched), 10..15),
+                (Ok(1), 15..16),
+                (Err(LexError::Unmatched), 16..17),
+            ]
+        );
+        assert_eq!(LexError::<&str>::Unmatched.to_string(), "no rule matches");
+        // `items` stops at the first unmatched byte
+        assert_eq!(lexer.items(s).count(), 2);
+    }
+
+    #[test]
+    fn lex_context() {
+        #[derive(Default)]
+        struct Env {
+            typedefs: HashSet<Vec<u8>>,
+            depth: usize,
+        }
+        #[derive(Clone, Debug, PartialEq)]
+        enum Tok {
+            Typedef,
+            TypeName,
+            Ident,
+            Open(usize),
+            Close(usize),
+            Semi,
+        }
+        owned_token!(Tok);
+        // The identifier after `typedef` names a type from then on
+        let lexer = LexerBuilder::<Option<Tok>, (), Env>::new()
+            .rule(
+                r"typedef\s+\l\w*",
+                Action::H(Box::new(|env, s| {
+                    let name = s.split(|x| x.is_ascii_whitespace()).next_back();
+                    env.typedefs.insert(name.unwrap().to_vec());
+                    Ok(Some(Tok::Typedef))
+                })),
+            )
+            .rule(
+                r"\l\w*",
+                Action::H(Box::new(|env, s| match env.typedefs.contains(s) {
+                    true => Ok(Some(Tok::TypeName)),
+                    false => Ok(Some(Tok::Ident)),
+                })),
+            )
+            .rule(
+                r"\{",
+                Action::H(Box::new(|env, _| {
+                    env.depth += 1;
+                    Ok(Some(Tok::Open(env.depth)))
+                })),
+            )
+            .rule(
+                r"\}",
+                Action::H(Box::new(|env, _| {
+                    env.depth -= 1;
+                    Ok(Some(Tok::Close(env.depth + 1)))
+                })),
+            )
+            .rule(r";", Action::C(Some(Tok::Semi)))
+            .rule(r"\s+", Action::C(None))
+            .build()
+            .unwrap();
+        let s = b"a; typedef a; { a; {} } a";
+        let mut env = Env::default();
+        let v: Vec<Tok> = lexer
+            .tokens_with(&mut env, s)
+            .filter_map(|(r, _)| r.unwrap())
+            .collect();
+        use Tok::*;
+        assert_eq!(
+            v,
+            vec![
+                Ident,
+                Semi,
+                Typedef,
+                Semi,
+                Open(1),
+                TypeName,
+                Semi,
+                Open(2),
+                Close(2),
+                Close(1),
+                TypeName,
+            ]
+        );
+        assert_eq!(env.depth, 0);
+        assert!(env.typedefs.contains(&b"a"[..]));
+        // The table outlives the run, so `a` stays a type name
+        let (r, _) = lexer.run_with(&mut env, b"a").unwrap();
+        assert_eq!(r, Ok(Some(TypeName)));
+        let r = lexer.stream_with(&mut env, &b"a"[..]).next().unwrap();
+        assert_eq!(r.unwrap(), (Ok(Some(TypeName)), 0..1));
+        // A fresh context knows no typedefs
+        let r = lexer.tokens(b"a").next().unwrap();
+        assert_eq!(r, (Ok(Some(Ident)), 0..1));
+    }
+
+    #[test]
+    fn lex_lexemes() {
+        #[derive(Clone, Debug, PartialEq)]
+        enum Kind {
+            Ident,
+            Number,
+            Space,
+        }
+        let lexer = LexerBuilder::<Kind, ()>::new()
+            .rule(r"\l\w*", Action::C(Kind::Ident))
+            .rule(r"\d+", Action::C(Kind::Number))
+            .rule(r"\s+", Action::C(Kind::Space))
+            .build()
+            .unwrap();
+        // The names borrow from `s`, not from the lexer or the iterator
+        owned_token!(Kind);
+        fn names<'a>(lexer: &Lexer<Kind, ()>, s: &'a str) -> Vec<&'a str> {
+            lexer
+                .tokens(s.as_bytes())
+                .lexemes()
+                .filter(|(r, _)| r == &Ok(Kind::Ident))
+                .map(|(_, x)| x.as_str().unwrap())
+                .collect()
+        }
+        let s = String::from("x1 42 \u{e9}t\u{e9} _y");
+        assert_eq!(names(&lexer, &s), vec!["x1", "t", "_y"]);
+        let v: Vec<_> = lexer.tokens(s.as_bytes()).lexemes().collect();
+        assert_eq!(
+            v[2].1,
+            Lexeme {
+                text: b"42",
+                span: 3..5
+            }
+        );
+        assert_eq!(v[4], (Err(LexError::Unmatched), v[4].1.clone()));
+        assert!(v[4].1.as_str().is_ok());
+        for (r, x) in v {
+            assert_eq!(x.text, &s.as_bytes()[x.span.clone()], "{:?}", r);
+        }
+    }
+
+    #[test]
+    fn lex_borrow() {
+        fn word(s: &[u8]) -> Result<Option<&str>, ()> {
+            from_utf8(s).map(Some).map_err(|_| ())
+        }
+        let lexer = LexerBuilder::<Option<&'static str>, ()>::new()
+            .rule(r"\w+", Action::B(Box::new(word)))
+            .rule(r"\s+", Action::C(None))
+            .build()
+            .unwrap();
+        let s = String::from("ab cd");
+        let v: Vec<_> = lexer.items(s.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(v, vec![Some("ab"), None, Some("cd")]);
+        assert!(std::ptr::eq(v[0].unwrap(), &s[..2]));
+        let v: Vec<_> =
+            lexer.stream(s.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(v[0], (Err(LexError::Borrow), 0..2));
+        assert_eq!(v[1], (Ok(None), 2..3));
+        // One lexer outlives every input it lexes
+        for i in 0..3 {
+            let s = format!("x{} y", i);
+            let v: Vec<_> = lexer.tokens(s.as_bytes()).collect();
+            assert_eq!(v[0], (Ok(Some(&s[..2])), 0..2));
+            let (r, _) = lexer.run(s.as_bytes()).unwrap();
+            assert_eq!(r, Ok(Some(&s[..2])));
+        }
+    }
+
+    #[test]
+    fn lex_policy() {
+        let lexer = |p| {
+            LexerBuilder::<usize, ()>::new()
+                .rule(r"/\*[\s\S]*\*/", Action::C(0))
+                .policy(p)
+                .rule(r"a+", Action::C(1))
+                .policy(p)
+                .rule(r"a+b", Action::C(2))
+                .rule(r"[\s\S]", Action::C(3))
+        };
+        let s = b"/* x */ y */aab";
+        let run = |p| {
+            let v: Vec<_> = lexer(p).build().unwrap().tokens(s).collect();
+            v.into_iter()
+                .map(|(r, x)| (r.unwrap(), x))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(Policy::Longest), vec![(0, 0..12), (2, 12..15)]);
+        // The comment ends at its first `*/`, but `a+b` still beats `a`
+        let shortest = run(Policy::Shortest);
+        assert_eq!(&shortest[..2], &[(0, 0..7), (3, 7..8)]);
+        assert_eq!(shortest.last(), Some(&(2, 12..15)));
+        // `a` wins before `a+b` can go on
+        let first = run(Policy::FirstAccept);
+        assert_eq!(&first[..2], &[(0, 0..7), (3, 7..8)]);
+        assert_eq!(
+            &first[first.len() - 3..],
+            &[(1, 12..13), (1, 13..14), (3, 14..15)]
+        );
+        let (_, warnings) =
+            lexer(Policy::FirstAccept).build_with_warnings().unwrap();
+        // Every match of `a+b` runs past where `a+` stops the scan
+        assert_eq!(
+            warnings,
+            vec![RuleWarning {
+                rule: 2,
+                by: vec![]
+            }]
+        );
+        // Lazy and loaded lexers keep to the policies
+        let lazy = lexer(Policy::Shortest).lazy(4).build().unwrap();
+        let v: Vec<_> = lazy.tokens(s).map(|(r, x)| (r.unwrap(), x)).collect();
+        assert_eq!(v, shortest);
+        let bytes = lexer(Policy::FirstAccept).build().unwrap().to_bytes();
+        let actions = (0..4).map(Action::C);
+        let loaded = Lexer::<usize, ()>::from_bytes(&bytes, actions).unwrap();
+        let v: Vec<_> =
+            loaded.tokens(s).map(|(r, x)| (r.unwrap(), x)).collect();
+        assert_eq!(v, first);
+
+        let v = vec![
+            (r"/\*[\s\S]*\*/", Action::C(0), Policy::Shortest),
+            (r"a+", Action::C(1), Policy::Shortest),
+            (r"a+b", Action::C(2), Policy::Longest),
+            (r"[\s\S]", Action::C(3), Policy::Longest),
+        ];
+        let lexer = Lexer::<usize, ()>::with_policies(v.into_iter()).unwrap();
+        let v: Vec<_> = lexer.tokens(s).map(|(r, x)| (r.unwrap(), x)).collect();
+        assert_eq!(v, shortest);
+        let e = LexerBuilder::<(), ()>::new()
+            .policy(Policy::Shortest)
+            .build();
+        assert_eq!(e.err(), Some(BuildError::NoRule("policy")));
+    }
+
+    #[test]
+    fn lex_dot() {
+        let builder = LexerBuilder::<usize, ()>::new()
+            .rule(r"(\w)+(\.(\w)+)?@\l+", Action::C(0))
+            .rule(r"\w+", Action::C(1));
+        let nfa = builder.nfa_dot().unwrap();
+        assert!(nfa.starts_with("digraph \"INITIAL\" {"));
+        assert!(nfa.contains("rule 1\"];"));
+        let dfa = builder.build().unwrap().to_dot();
+        assert!(dfa.contains("[label=\"\\\\w\"];"));
+        assert!(dfa.contains("[label=\"@\"];"));
+        assert!(dfa.contains("[label=\"\\\\l\"];"));
+        assert!(dfa.contains("rule 0\"];") && dfa.contains("rule 1\"];"));
+        let lazy = LexerBuilder::<usize, ()>::new()
+            .rule(r"(\w)+(\.(\w)+)?@\l+", Action::C(0))
+            .rule(r"\w+", Action::C(1))
+            .lazy(4)
+            .build()
+            .unwrap();
+        assert_eq!(lazy.to_dot(), dfa);
+    }
+
+    #[test]
+    fn lex_rule_regex() {
+        let builder = LexerBuilder::<usize, ()>::new()
+            .define("digit", r"[0-9]")
+            .rule(r"{digit}+", Action::C(0))
+            .rule(r"\d\d*", Action::C(1))
+            .rule(r"0x[0-9a-f]+", Action::C(2));
+        let [a, b, c] = [0, 1, 2].map(|i| builder.rule_regex(i).unwrap());
+        assert!(a.is_equivalent(&b));
+        assert!(!a.is_subset(&c) && a.intersection_is_empty(&c));
+        assert_eq!(c.subset_counterexample(&a), Some(b"0x0".to_vec()));
+        assert_eq!(a.find(b"x 42"), Some(2..4));
+        let e = LexerBuilder::<usize, ()>::new()
+            .rule(r"{digit}", Action::C(0))
+            .rule_regex(0)
+            .err()
+            .unwrap();
+        assert!(matches!(e, BuildError::Regex(_)));
+        let e = builder.rule_regex(3).err();
+        assert_eq!(e, Some(BuildError::UnknownRule(3)));
+    }
+
+    #[test]
+    fn lex_definitions_error() {
+        let build = |v: Vec<(&'static str, &'static str)>, rule| {
+            v.into_iter()
+                .fold(LexerBuilder::new(), |b, (x, r)| b.define(x, r))
+                .rule(rule, Action::<(), ()>::C(()))
+                .build()
+                .err()
+                .unwrap()
+        };
+        let build = |v, rule| match build(v, rule) {
+            BuildError::Regex(e) => e,
+            e => panic!("{}", e),
+        };
+        let e = build(vec![("A", "a")], r"{A}{B}");
+        assert_eq!(e.pattern, Pattern::Rule(0));
+        assert_eq!(e.offset, 3);
+        assert_eq!(e.kind, RegexErrorKind::UndefinedName("B".to_string()));
+        let e = build(vec![("A", "a{B}"), ("B", "b|{A}")], r"x{A}");
+        assert_eq!(e.pattern, Pattern::Definition("B".to_string()));
+        assert_eq!(e.offset, 2);
+        assert_eq!(e.kind, RegexErrorKind::RecursiveName("A".to_string()));
+        assert_eq!(
+            e.to_string(),
+            "definition `B`: recursive name `A` at byte 2"
+        );
+        let e = build(vec![("A", "a("), ("B", "b")], r"{B}");
+        assert_eq!(e.pattern, Pattern::Definition("A".to_string()));
+        assert_eq!(e.kind, RegexErrorKind::UnclosedGroup);
+        let e = build(vec![("1A", "a")], r"a");
+        assert_eq!(e.kind, RegexErrorKind::InvalidName);
+    }
 }
@@ -1,5 +1,6 @@
-use crate::automata::IR;
-use std::{iter::once, ops::RangeInclusive};
+use super::utf8::utf8_sequences;
+use crate::{automata::IR, ParseRegexError};
+use std::ops::RangeInclusive;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum MetaCharacter {
@@ -11,70 +12,194 @@ pub(crate) enum MetaCharacter {
     Dot,
 }
 
+impl MetaCharacter {
+    fn ranges(self, max: u32) -> Vec<RangeInclusive<u32>> {
+        let r = |a: u8, b: u8| a as u32..=b as u32;
+        match self {
+            MetaCharacter::D => vec![r(b'0', b'9')],
+            MetaCharacter::H => {
+                vec![r(b'a', b'f'), r(b'A', b'F'), r(b'0', b'9')]
+            }
+            MetaCharacter::L => {
+                vec![r(b'a', b'z'), r(b'A', b'Z'), r(b'_', b'_')]
+            }
+            MetaCharacter::S => {
+                vec![
+                    r(b' ', b' '),
+                    r(b'\r', b'\r'),
+                    r(b'\n', b'\n'),
+                    r(b'\t', b'\t'),
+                ]
+            }
+            MetaCharacter::W => {
+                vec![r(b'a', b'z'), r(b'A', b'Z'), r(b'0', b'9'), r(b'_', b'_')]
+            }
+            MetaCharacter::Dot => vec![0..=0x09, 0x0B..=max],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PosixClass {
+    Alnum,
+    Alpha,
+    Blank,
+    Cntrl,
+    Digit,
+    Graph,
+    Lower,
+    Print,
+    Punct,
+    Space,
+    Upper,
+    Xdigit,
+}
+
+impl PosixClass {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        use PosixClass::*;
+        match name {
+            "alnum" => Some(Alnum),
+            "alpha" => Some(Alpha),
+            "blank" => Some(Blank),
+            "cntrl" => Some(Cntrl),
+            "digit" => Some(Digit),
+            "graph" => Some(Graph),
+            "lower" => Some(Lower),
+            "print" => Some(Print),
+            "punct" => Some(Punct),
+            "space" => Some(Space),
+            "upper" => Some(Upper),
+            "xdigit" => Some(Xdigit),
+            _ => None,
+        }
+    }
+
+    fn ranges(self) -> Vec<RangeInclusive<u32>> {
+        use PosixClass::*;
+        let r = |a: u8, b: u8| a as u32..=b as u32;
+        match self {
+            Alnum => vec![r(b'0', b'9'), r(b'A', b'Z'), r(b'a', b'z')],
+            Alpha => vec![r(b'A', b'Z'), r(b'a', b'z')],
+            Blank => vec![r(b'\t', b'\t'), r(b' ', b' ')],
+            Cntrl => vec![r(0x00, 0x1F), r(0x7F, 0x7F)],
+            Digit => vec![r(b'0', b'9')],
+            Graph => vec![r(0x21, 0x7E)],
+            Lower => vec![r(b'a', b'z')],
+            Print => vec![r(0x20, 0x7E)],
+            Punct => {
+                vec![r(0x21, 0x2F), r(0x3A, 0x40), r(0x5B, 0x60), r(0x7B, 0x7E)]
+            }
+            Space => vec![r(0x09, 0x0D), r(b' ', b' ')],
+            Upper => vec![r(b'A', b'Z')],
+            Xdigit => vec![r(b'0', b'9'), r(b'A', b'F'), r(b'a', b'f')],
+        }
+    }
+}
+
+// Items hold bytes, or codepoints when the class is in Unicode mode
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) enum CharacterClassItem {
-    Byte(u8),
+    Char(u32),
     Meta(MetaCharacter),
-    ByteRange(RangeInclusive<u8>),
+    NegatedMeta(MetaCharacter),
+    Posix(PosixClass),
+    Range(RangeInclusive<u32>),
 }
 
-impl From<CharacterClassItem> for CharacterClass {
-    fn from(value: CharacterClassItem) -> Self {
-        Self {
-            negated: false,
-            items: vec![value],
+// Sorted, disjoint and non-adjacent ranges
+fn normalize(mut v: Vec<RangeInclusive<u32>>) -> Vec<RangeInclusive<u32>> {
+    v.sort_by_key(|r| *r.start());
+    let mut w: Vec<RangeInclusive<u32>> = Vec::new();
+    for r in v {
+        match w.last_mut() {
+            Some(l) if *r.start() <= l.end().saturating_add(1) => {
+                *l = *l.start()..=*l.end().max(r.end());
+            }
+            _ => w.push(r),
+        }
+    }
+    w
+}
+
+fn complement(
+    v: Vec<RangeInclusive<u32>>,
+    max: u32,
+) -> Vec<RangeInclusive<u32>> {
+    let mut lo = 0;
+    let mut u = Vec::new();
+    for r in normalize(v) {
+        if lo < *r.start() {
+            u.push(lo..=*r.start() - 1);
         }
+        lo = *r.end() + 1;
     }
+    if lo <= max {
+        u.push(lo..=max);
+    }
+    u
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct CharacterClass {
     negated: bool,
+    unicode: bool,
     items: Vec<CharacterClassItem>,
 }
 
 impl CharacterClass {
-    pub(crate) fn new(negated: bool, items: Vec<CharacterClassItem>) -> Self {
-        Self { negated, items }
+    pub(crate) fn new(
+        negated: bool,
+        unicode: bool,
+        items: Vec<CharacterClassItem>,
+    ) -> Self {
+        Self {
+            negated,
+            unicode,
+            items,
+        }
     }
-}
 
-use CharacterClassItem::*;
-impl From<CharacterClass> for Vec<u8> {
-    fn from(value: CharacterClass) -> Self {
-        let v: Self = value
+    fn ranges(&self) -> Vec<RangeInclusive<u32>> {
+        use CharacterClassItem::*;
+        let max = if self.unicode { char::MAX as u32 } else { 0xFF };
+        let v = self
             .items
-            .into_iter()
+            .iter()
             .flat_map(|item| match item {
-                Byte(x) => vec![x],
-                Meta(m) => match m {
-                    MetaCharacter::D => (b'0'..=b'9').collect(),
-                    MetaCharacter::H => (b'a'..=b'f')
-                        .chain(b'A'..=b'F')
-                        .chain(b'0'..=b'9')
-                        .collect(),
-                    MetaCharacter::L => (b'a'..=b'z')
-                        .chain(b'A'..=b'Z')
-                        .chain(once(b'_'))
-                        .collect(),
-                    MetaCharacter::S => vec![b' ', b'\r', b'\n', b'\t'],
-                    MetaCharacter::W => (b'a'..=b'z')
-                        .chain(b'A'..=b'Z')
-                        .chain(b'0'..=b'9')
-                        .chain(once(b'_'))
-                        .collect(),
-                    MetaCharacter::Dot => {
-                        (0..=255u8).filter(|&x| x != b'\n').collect()
-                    }
-                },
-                ByteRange(r) => r.into_iter().collect(),
+                Char(x) => vec![*x..=*x],
+                Meta(m) => m.ranges(max),
+                NegatedMeta(m) => complement(m.ranges(max), max),
+                Posix(p) => p.ranges(),
+                Range(r) => vec![r.clone()],
             })
             .collect();
-        if value.negated {
-            (0..=255u8).filter(|x| !v.contains(x)).collect()
+        if self.negated {
+            complement(v, max)
         } else {
-            v
+            normalize(v)
+        }
+    }
+}
+
+impl From<CharacterClass> for IR {
+    fn from(value: CharacterClass) -> Self {
+        use IR::*;
+        let v = value.ranges();
+        if !value.unicode {
+            return L(v
+                .into_iter()
+                .flat_map(|r| *r.start() as u8..=*r.end() as u8)
+                .collect());
+        }
+        let mut seqs = Vec::new();
+        for r in v {
+            utf8_sequences(r, &mut seqs);
         }
+        U(seqs
+            .into_iter()
+            .map(|seq| C(seq.into_iter().map(|r| L(r.collect())).collect()))
+            .collect())
     }
 }
 
@@ -86,26 +211,76 @@ pub(crate) enum AST {
     Star(Box<AST>),
     Plus(Box<AST>),
     QnMk(Box<AST>),
+    Counted(Box<AST>, usize, Option<usize>),
+    Intersection(Vec<AST>),
+    Complement(Box<AST>),
+    // Capture group numbered from 1 by its `(`
+    Group(usize, Box<AST>),
+    // `{NAME}` and its byte offset in the pattern
+    Name(String, usize),
 }
 
-impl From<AST> for IR {
-    fn from(ast: AST) -> Self {
+// Resolves `{NAME}` at a byte offset to its IR
+pub(crate) type Names<'a> =
+    dyn FnMut(&str, usize) -> Result<IR, ParseRegexError> + 'a;
+
+impl AST {
+    // Groups only capture when `capture` is set; definitions never do
+    pub(crate) fn into_ir(
+        self,
+        capture: bool,
+        name: &mut Names,
+    ) -> Result<IR, ParseRegexError> {
         use AST::*;
         use IR::*;
 
-        match ast {
-            WithCharacterClass(v) => L(v.into()),
-            Concatenation(v) => C(v.into_iter().map(Self::from).collect()),
-            Alternation(v) => U(v.into_iter().map(Self::from).collect()),
-            Star(a) => K(Box::new(Self::from(*a))),
+        let all = |v: Vec<AST>, name: &mut Names| {
+            v.into_iter()
+                .map(|x| x.into_ir(capture, name))
+                .collect::<Result<Vec<IR>, ParseRegexError>>()
+        };
+        // Alternatives are listed in order of preference for captures,
+        // so quantifiers are greedy
+        Ok(match self {
+            WithCharacterClass(v) => v.into(),
+            Concatenation(v) => C(all(v, name)?),
+            Alternation(v) => U(all(v, name)?),
+            Star(a) => K(Box::new(a.into_ir(capture, name)?)),
             Plus(a) => {
-                let ir = Self::from(*a);
+                let ir = a.into_ir(capture, name)?;
                 C(vec![ir.clone(), K(Box::new(ir))])
             }
             QnMk(a) => {
-                let ir = Self::from(*a);
-                U(vec![E, ir])
+                let ir = a.into_ir(capture, name)?;
+                U(vec![ir, E])
             }
-        }
+            Intersection(v) => I(all(v, name)?),
+            Complement(a) => N(Box::new(a.into_ir(capture, name)?)),
+            Group(i, a) if capture => T(i, Box::new(a.into_ir(capture, name)?)),
+            Group(_, a) => a.into_ir(capture, name)?,
+            Name(x, offset) => name(&x, offset)?,
+            Counted(a, m, n) => {
+                let ir = a.into_ir(capture, name)?;
+                let mut v = vec![ir.clone(); m];
+                match n {
+                    None => v.push(K(Box::new(ir))),
+                    // Nest optional copies: x(x(x)?)? rather than x?x?x?
+                    Some(n) => {
+                        if let Some(opt) = (m..n).fold(None, |acc, _| {
+                            let mut w = vec![ir.clone()];
+                            w.extend(acc);
+                            Some(U(vec![C(w), E]))
+                        }) {
+                            v.push(opt);
+                        }
+                    }
+                }
+                match v.len() {
+                    0 => E,
+                    1 => v.pop().unwrap(),
+                    _ => C(v),
+                }
+            }
+        })
     }
 }
@@ -1,18 +1,131 @@
-use self::parser::ast_regex;
-use crate::{automata::IR, combinator::Parser, ParseRegexError};
-use std::str::FromStr;
+use self::{ast::AST, parser::ast_regex};
+use crate::{automata::IR, ParseRegexError, Pattern, RegexErrorKind};
+use std::{collections::HashMap, str::FromStr};
 
 mod ast;
 mod parser;
+mod utf8;
+
+// How patterns are read. In Unicode mode they match UTF-8 encoded
+// codepoints; with `boolean` set, `&` and `~` are operators rather than
+// literal bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Syntax {
+    pub(crate) unicode: bool,
+    pub(crate) boolean: bool,
+}
+
+impl IR {
+    pub(crate) fn from_regex(
+        s: &str,
+        syntax: Syntax,
+    ) -> Result<Self, ParseRegexError> {
+        Definitions::new(syntax).ir(s, Pattern::Rule(0))
+    }
+}
 
 impl FromStr for IR {
     type Err = ParseRegexError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        ast_regex()
-            .run(s.as_bytes())
-            .and_then(|(ast, t)| Some(IR::from(ast)).filter(|_| t.is_empty()))
-            .ok_or_else(|| ParseRegexError(s.to_owned()))
+        Self::from_regex(s, Syntax::default())
+    }
+}
+
+// Named sub-patterns, parsed up front and lowered to IR on first use
+pub(crate) struct Definitions {
+    syntax: Syntax,
+    asts: HashMap<String, AST>,
+    irs: HashMap<String, IR>,
+}
+
+impl Definitions {
+    pub(crate) fn new(syntax: Syntax) -> Self {
+        Self {
+            syntax,
+            asts: HashMap::new(),
+            irs: HashMap::new(),
+        }
+    }
+
+    fn parse(
+        &self,
+        s: &str,
+        pattern: &Pattern,
+    ) -> Result<AST, ParseRegexError> {
+        ast_regex(s, self.syntax).map_err(|(offset, kind)| {
+            ParseRegexError {
+                pattern: pattern.clone(),
+                offset,
+                kind,
+            }
+        })
+    }
+
+    pub(crate) fn define(
+        &mut self,
+        name: &str,
+        s: &str,
+    ) -> Result<(), ParseRegexError> {
+        let pattern = Pattern::Definition(name.to_owned());
+        let valid = name.bytes().enumerate().all(|(i, x)| {
+            x == b'_'
+                || x.is_ascii_alphabetic()
+                || (i > 0 && x.is_ascii_digit())
+        });
+        if name.is_empty() || !valid {
+            return Err(ParseRegexError {
+                pattern,
+                offset: 0,
+                kind: RegexErrorKind::InvalidName,
+            });
+        }
+        let ast = self.parse(s, &pattern)?;
+        self.asts.insert(name.to_owned(), ast);
+        Ok(())
+    }
+
+    pub(crate) fn ir(
+        &mut self,
+        s: &str,
+        pattern: Pattern,
+    ) -> Result<IR, ParseRegexError> {
+        let ast = self.parse(s, &pattern)?;
+        self.lower(ast, &pattern, &mut Vec::new())
+    }
+
+    // `stack` holds the definitions being lowered, to catch recursion.
+    // Only the groups of the rule itself capture.
+    fn lower(
+        &mut self,
+        ast: AST,
+        pattern: &Pattern,
+        stack: &mut Vec<String>,
+    ) -> Result<IR, ParseRegexError> {
+        ast.into_ir(stack.is_empty(), &mut |name, offset| {
+            let error = |kind| ParseRegexError {
+                pattern: pattern.clone(),
+                offset,
+                kind,
+            };
+            if let Some(ir) = self.irs.get(name) {
+                return Ok(ir.clone());
+            }
+            if stack.iter().any(|x| x == name) {
+                return Err(error(RegexErrorKind::RecursiveName(
+                    name.to_owned(),
+                )));
+            }
+            let ast = self.asts.get(name).cloned().ok_or_else(|| {
+                error(RegexErrorKind::UndefinedName(name.to_owned()))
+            })?;
+            stack.push(name.to_owned());
+            let ir =
+                self.lower(ast, &Pattern::Definition(name.to_owned()), stack)?;
+            stack.pop();
+            self.irs.insert(name.to_owned(), ir.clone());
+            Ok(ir)
+        })
     }
 }
 
@@ -24,10 +137,28 @@ mod tests {
         combinator::Parser,
     };
 
+    const BOOLEAN: Syntax = Syntax {
+        unicode: false,
+        boolean: true,
+    };
+
     fn parser_from_regex(
         r: &str,
     ) -> Result<ParserAutomaton<DFA>, ParseRegexError> {
-        let ir = r.parse()?;
+        let ir = IR::from_regex(r, BOOLEAN)?;
+        let nfa = NFABuilder::new().ir(&ir).build();
+        let dfa = DFA::new(&nfa);
+        Ok(ParserAutomaton(dfa))
+    }
+
+    fn parser_from_unicode_regex(
+        r: &str,
+    ) -> Result<ParserAutomaton<DFA>, ParseRegexError> {
+        let syntax = Syntax {
+            unicode: true,
+            ..BOOLEAN
+        };
+        let ir = IR::from_regex(r, syntax)?;
         let nfa = NFABuilder::new().ir(&ir).build();
         let dfa = DFA::new(&nfa);
         Ok(ParserAutomaton(dfa))
@@ -35,8 +166,9 @@ mod tests {
 
     #[test]
     fn regex_new() {
-        assert!(parser_from_regex(r"\w?").is_err());
+        assert!(parser_from_regex(r"\w?").is_ok());
         assert!(parser_from_regex(r"a(b(c(d)+)*)?(e(f)?(g)?)*").is_ok());
+        assert!(parser_from_regex(r"ab(c|d+)?e*").is_ok());
     }
 
     #[test]
@@ -86,4 +218,175 @@ mod tests {
             assert_eq!(x.accept(s.as_bytes()), b, "{} {} {}", r, s, b);
         }
     }
+
+    #[test]
+    fn regex_counted() {
+        let data = [
+            (r"a(b){3}c", "abbbc", true),
+            (r"a(b){3}c", "abbc", false),
+            (r"a(b){3}c", "abbbbc", false),
+            (r"a(b){0}c", "ac", true),
+            (r"a(b){2,}c", "abc", false),
+            (r"a(b){2,}c", "abbc", true),
+            (r"a(b){2,}c", "abbbbbbc", true),
+            (r"([0-7]){1,3}", "", false),
+            (r"([0-7]){1,3}", "7", true),
+            (r"([0-7]){1,3}", "777", true),
+            (r"([0-7]){1,3}", "7777", false),
+            (r"([0-7]){1,3}", "8", false),
+            (r"(\h){4}", "00fF", true),
+            (r"(\h){4}", "00fG", false),
+            (r"(ab|c){1,2}", "abc", true),
+            (r"(ab|c){1,2}", "cab", true),
+            (r"(ab|c){1,2}", "cabc", false),
+        ];
+        for (r, s, b) in data {
+            let x = parser_from_regex(r).unwrap();
+            assert_eq!(x.accept(s.as_bytes()), b, "{} {} {}", r, s, b);
+        }
+        assert!(parser_from_regex(r"a{2}").is_ok());
+        assert!(parser_from_regex(r"(a){3,2}").is_err());
+        assert!(parser_from_regex(r"(a){,2}").is_err());
+    }
+
+    #[test]
+    fn regex_postfix() {
+        let data = [
+            (r"ab*", "a", true),
+            (r"ab*", "abbb", true),
+            (r"ab*", "abab", false),
+            (r"\w?abc\w?", "xabcy", true),
+            (r"a[bc]+d", "abcbd", true),
+            (r"a[bc]+d", "ad", false),
+            (r"(ab)+", "abab", true),
+            (r"(ab)+", "aba", false),
+            (r"ab|cd*", "ab", true),
+            (r"ab|cd*", "cddd", true),
+            (r"ab|cd*", "abd", false),
+            (r"\-?\d{2}", "-12", true),
+            (r"\-?\d{2}", "123", false),
+        ];
+        for (r, s, b) in data {
+            let x = parser_from_regex(r).unwrap();
+            assert_eq!(x.accept(s.as_bytes()), b, "{} {} {}", r, s, b);
+        }
+    }
+
+    #[test]
+    fn regex_unicode() {
+        let data = [
+            (r"[α-ω]+", "αβγω", true),
+            (r"[α-ω]+", "αβγΩ", false),
+            (r"[^α-ω]", "Ω", true),
+            (r"[^α-ω]", "β", false),
+            (r"[^α-ω]", "a", true),
+            (r"[^a]", "€", true),
+            (r"[^a]", "a", false),
+            (r"café", "café", true),
+            (r"café?", "caf", true),
+            (r"caf[eé]", "cafe", true),
+            (r"...", "a€𝄞", true),
+            (r"...", "a€", false),
+            (r"[a-z𝄞]{2}", "𝄞a", true),
+            (r"\w+", "snowman", true),
+            (r"\w+", "☃", false),
+        ];
+        for (r, s, b) in data {
+            let x = parser_from_unicode_regex(r).unwrap();
+            assert_eq!(x.accept(s.as_bytes()), b, "{} {} {}", r, s, b);
+        }
+        // A codepoint never matches a partial or invalid encoding
+        let x = parser_from_unicode_regex(r".").unwrap();
+        assert!(!x.accept(&[0xCE]));
+        assert!(!x.accept(&[0xFF]));
+        assert!(!x.accept(&[0xED, 0xA0, 0x80]));
+        // Byte mode still reads the pattern byte by byte
+        let x = parser_from_regex(r"[^a]").unwrap();
+        assert!(!x.accept("€".as_bytes()));
+        assert!(x.accept(&[0xFF]));
+    }
+
+    #[test]
+    fn regex_escapes() {
+        let data = [
+            (r"a\nb", "a\nb", true),
+            (r"\t\r\f\v\a\b", "\t\r\x0c\x0b\x07\x08", true),
+            (r"\x41\x{42}\103", "ABC", true),
+            (r"\0", "\0", true),
+            (r"[\x00-\x1f]+", "\x01\n\x1f", true),
+            (r"[\x00-\x1f]+", "\x01 ", false),
+            (r"\D+", "ab-", true),
+            (r"\D", "5", false),
+            (r"\S+", "a.b", true),
+            (r"\S", " ", false),
+            (r"\W", "-", true),
+            (r"\W", "_", false),
+            (r"[\W\d]+", "-9", true),
+            (r"[[:alpha:]]+", "abXY", true),
+            (r"[[:alpha:]]", "1", false),
+            (r"[[:upper:][:digit:]]+", "A1B2", true),
+            (r"[[:upper:][:digit:]]", "a", false),
+            (r"[^[:space:]]+", "a!b", true),
+            (r"[^[:space:]]", "\x0b", false),
+            (r"[[:punct:]]+", "!/:@[`{~", true),
+            (r"[[:punct:]]", "a", false),
+            (r"[[:xdigit:]]{2}", "fF", true),
+            (r"//.*\n", "// comment\n", true),
+        ];
+        for (r, s, b) in data {
+            let x = parser_from_regex(r).unwrap();
+            assert_eq!(x.accept(s.as_bytes()), b, "{:?} {:?} {}", r, s, b);
+        }
+        let x = parser_from_regex(r"\xff\200").unwrap();
+        assert!(x.accept(&[0xFF, 0x80]));
+        let x = parser_from_unicode_regex(r"\x{3b1}\xe9").unwrap();
+        assert!(x.accept("αé".as_bytes()));
+    }
+
+    #[test]
+    fn regex_boolean() {
+        let data = [
+            (r"\w+&[^\d]+", "abc", true),
+            (r"\w+&[^\d]+", "ab1", false),
+            (r"\l\w*&~(if|else)", "iff", true),
+            (r"\l\w*&~(if|else)", "if", false),
+            (r"\l\w*&~(if|else)", "else", false),
+            (r"~a", "", true),
+            (r"~a", "a", false),
+            (r"~a", "aa", true),
+            (r"x~(ab)*y", "xaby", false),
+            (r"x~(ab)*y", "xaay", true),
+            (r"a|b&c", "a", true),
+            (r"a|b&c", "b", false),
+            (r"(a|b)&(b|c)", "b", true),
+            (r"(a|b)&(b|c)", "a", false),
+        ];
+        for (r, s, b) in data {
+            let x = parser_from_regex(r).unwrap();
+            assert_eq!(x.accept(s.as_bytes()), b, "{} {} {}", r, s, b);
+        }
+        // Only valid UTF-8 is outside a pattern in Unicode mode
+        let x = parser_from_regex(r"~a").unwrap();
+        assert!(x.accept(&[0xFF]) && x.accept(&[0xC3]));
+        let x = parser_from_unicode_regex(r"~a").unwrap();
+        assert!(!x.accept(&[0xFF]) && !x.accept(&[0xC3]));
+        assert!(x.accept("é".as_bytes()) && x.accept("aa".as_bytes()));
+        assert!(!x.accept("a".as_bytes()));
+        // Without the operators both are plain characters
+        let ir: IR = r"a&~b|\&".parse().unwrap();
+        let x = ParserAutomaton(DFA::from_ir(&ir));
+        assert!(x.accept("a&~b".as_bytes()) && x.accept("&".as_bytes()));
+    }
+
+    #[test]
+    fn regex_block_comment() {
+        let x = parser_from_regex(r"/\*~([\s\S]*\*/[\s\S]*)\*/").unwrap();
+        assert!(x.accept("/* a\nb\n */".as_bytes()));
+        assert!(x.accept("/**/".as_bytes()));
+        assert!(x.accept("/* a * / b ***/".as_bytes()));
+        assert!(!x.accept("/*/".as_bytes()));
+        assert!(!x.accept("/* a */ b /* c */".as_bytes()));
+        let (_, t) = x.run("/* a */ b /* c */".as_bytes()).unwrap();
+        assert_eq!(t, " b /* c */".as_bytes());
+    }
 }
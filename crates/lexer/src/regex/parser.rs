@@ -1,192 +1,741 @@
-use std::{
-    ops::RangeInclusive,
-    rc::{Rc, Weak},
-};
+use std::rc::{Rc, Weak};
 
-use crate::combinator::*;
+use crate::{
+    combinator::*,
+    RegexErrorKind::{self, *},
+};
 
-use super::ast::{
-    CharacterClass, CharacterClassItem, MetaCharacter,
-    AST::{self, *},
+use super::{
+    ast::{
+        CharacterClass, CharacterClassItem, MetaCharacter, PosixClass,
+        AST::{self, *},
+    },
+    Syntax,
 };
 
-const ESCAPED: [u8; 14] = [
+const ESCAPED: [u8; 16] = [
     b'^', b'-', b'.', b'*', b'+', b'?', b'|', b'(', b')', b'[', b']', b'{',
-    b'}', b'\\',
+    b'}', b'\\', b'&', b'~',
 ];
 
-fn meta() -> impl Parser<Item = MetaCharacter> {
+// The largest count in `{m,n}`, since each repetition is a copy of the
+// repeated automaton
+const MAX_COUNT: usize = 1000;
+
+// Parsers yield `Err` once they have committed to a construct that turns
+// out to be malformed, and `None` when it does not start here
+type Parsed<T> = Result<T, Failure<RegexErrorKind>>;
+
+type Recursive = Weak<Box<dyn Parser<Item = Parsed<AST>>>>;
+
+fn fail<T>(left: usize, kind: RegexErrorKind) -> Parsed<T> {
+    Err(Failure { left, kind })
+}
+
+fn ok<P: Parser>(p: P) -> impl Parser<Item = Parsed<P::Item>> {
+    p.map(Ok)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Quantifier {
+    Star,
+    Plus,
+    QnMk,
+    Counted(usize, Option<usize>),
+}
+
+impl Quantifier {
+    fn apply(self, ast: AST) -> AST {
+        let ast = Box::new(ast);
+        match self {
+            Quantifier::Star => Star(ast),
+            Quantifier::Plus => Plus(ast),
+            Quantifier::QnMk => QnMk(ast),
+            Quantifier::Counted(m, n) => Counted(ast, m, n),
+        }
+    }
+}
+
+// A UTF-8 encoded codepoint in Unicode mode, a byte otherwise
+struct Literal(bool);
+
+impl Parser for Literal {
+    type Item = u32;
+    fn run<'a>(&self, s: &'a [u8]) -> Option<(Self::Item, &'a [u8])> {
+        if !self.0 || s.is_empty() {
+            return ParserChar.map(u32::from).run(s);
+        }
+        let n = crate::char_len(s);
+        let c = std::str::from_utf8(&s[..n]).ok()?.chars().next()?;
+        Some((c as u32, &s[n..]))
+    }
+}
+
+fn meta(x: u8) -> Option<MetaCharacter> {
     use MetaCharacter::*;
-    satisfy(b'\\')
-        .then(ParserChar.filter_map(|x| match x {
-            b'd' => Some(D),
-            b'h' => Some(H),
-            b'l' => Some(L),
-            b's' => Some(S),
-            b'w' => Some(W),
-            _ => None,
-        }))
-        .or(satisfy(b'.').map(|_| Dot))
-}
-
-fn char() -> impl Parser<Item = u8> {
-    satisfy(b'\\')
-        .then(ParserChar.filter(|x| ESCAPED.contains(x)))
-        .or(ParserChar.filter(|x| !ESCAPED.contains(x)))
-}
-
-fn atom() -> impl Parser<Item = CharacterClassItem> {
-    char()
-        .map(CharacterClassItem::Byte)
-        .or(meta().map(CharacterClassItem::Meta))
-}
-
-fn ast_ccls() -> impl Parser<Item = AST> {
-    between(
-        optional(satisfy(b'^'))
-            .zip_with(
-                char()
-                    .zip_with(satisfy(b'-').then(char()), RangeInclusive::new)
-                    .map(CharacterClassItem::ByteRange)
-                    .or(atom())
-                    .collect(),
-                |opt, v| {
-                    if v.is_empty() {
-                        None
-                    } else {
-                        Some(WithCharacterClass(CharacterClass::new(
-                            opt.is_some(),
-                            v,
-                        )))
-                    }
-                },
-            )
-            .filter_map(|x| x),
-        b'[',
-        b']',
+    match x {
+        b'd' => Some(D),
+        b'h' => Some(H),
+        b'l' => Some(L),
+        b's' => Some(S),
+        b'w' => Some(W),
+        _ => None,
+    }
+}
+
+fn control(x: u8) -> Option<u32> {
+    match x {
+        b'a' => Some(0x07),
+        b'b' => Some(0x08),
+        b'f' => Some(0x0C),
+        b'n' => Some(0x0A),
+        b'r' => Some(0x0D),
+        b't' => Some(0x09),
+        b'v' => Some(0x0B),
+        _ => None,
+    }
+}
+
+// Between `min` and `max` digits in `radix`
+fn digits(radix: u32, min: usize, max: usize) -> impl Parser<Item = u32> {
+    ParserChar
+        .filter(move |x| (*x as char).is_digit(radix))
+        .at_most(max)
+        .filter_map(move |v| match v.len() >= min {
+            true => {
+                u32::from_str_radix(std::str::from_utf8(&v).ok()?, radix).ok()
+            }
+            false => None,
+        })
+}
+
+// What follows a `\`, or why it is no escape
+fn escaped(
+    syntax: Syntax,
+) -> impl Parser<Item = Result<CharacterClassItem, RegexErrorKind>> {
+    use CharacterClassItem::*;
+    // A byte in byte mode, a scalar value in Unicode mode
+    let value = move |x| match syntax.unicode {
+        true if char::from_u32(x).is_some() => Ok(Char(x)),
+        false if x <= 0xFF => Ok(Char(x)),
+        _ => Err(OutOfRange),
+    };
+    // \xHH or \x{H...}
+    let hex = satisfy(b'x').then(
+        between(digits(16, 1, 6), b'{', b'}')
+            .or(digits(16, 2, 2))
+            .map(value)
+            .or(ParserEmpty.map(|_| Err(InvalidHexEscape))),
+    );
+    // \o, \oo or \ooo
+    digits(8, 1, 3).map(value).or(hex).or(ParserChar.map(|x| {
+        // `$` needs no escape, but takes one where a search reads an anchor
+        if ESCAPED.contains(&x) || x == b'$' {
+            Ok(Char(x as u32))
+        } else if let Some(c) = control(x) {
+            Ok(Char(c))
+        } else if let Some(m) = meta(x) {
+            Ok(Meta(m))
+        } else if let Some(m) =
+            meta(x.to_ascii_lowercase()).filter(|_| x.is_ascii_uppercase())
+        {
+            Ok(NegatedMeta(m))
+        } else {
+            Err(InvalidEscape(x))
+        }
+    }))
+}
+
+fn escape(syntax: Syntax) -> impl Parser<Item = Parsed<CharacterClassItem>> {
+    ParserLeft.skip(satisfy(b'\\')).zip_with(
+        optional(escaped(syntax)),
+        |left, x| match x {
+            Some(x) => x.map_err(|kind| Failure { left, kind }),
+            None => fail(left - 1, UnexpectedEnd),
+        },
     )
 }
 
-pub(crate) fn ast_regex() -> Rc<Box<dyn Parser<Item = AST>>> {
-    Rc::new_cyclic(|me: &Weak<Box<dyn Parser<Item = AST>>>| {
-        Box::new(
-            intersperse(
-                atom()
-                    .map(|x| WithCharacterClass(CharacterClass::from(x)))
-                    .or(ast_ccls())
-                    .or(between(me.clone(), b'(', b')')
-                        .zip_with(ParserChar, |ast, x| {
-                            let ast = Box::new(ast);
-                            match x {
-                                b'*' => Some(Star(ast)),
-                                b'+' => Some(Plus(ast)),
-                                b'?' => Some(QnMk(ast)),
-                                _ => None,
-                            }
-                        })
-                        .filter_map(|x| x))
-                    .collect()
-                    .filter_map(|mut v| match v.len() {
-                        0 => None,
-                        1 => v.pop(),
-                        _ => Some(Concatenation(v)),
-                    }),
-                b'|',
-            )
-            .filter_map(|mut v| match v.len() {
-                0 => None,
-                1 => v.pop(),
-                _ => Some(Alternation(v)),
-            }),
+// Bytes that must be escaped to stand for themselves
+fn special(syntax: Syntax, x: u8) -> bool {
+    match x {
+        b'&' | b'~' => syntax.boolean,
+        _ => ESCAPED.contains(&x),
+    }
+}
+
+fn atom(syntax: Syntax) -> impl Parser<Item = Parsed<CharacterClassItem>> {
+    use CharacterClassItem::*;
+    let special = ParserChar.filter(move |x| special(syntax, *x));
+    escape(syntax)
+        .or(satisfy(b'.').map(|_| Ok(Meta(MetaCharacter::Dot))))
+        .or(ParserLeft
+            .zip_with(special, |left, x| fail(left, UnexpectedByte(x))))
+        .or(ok(Literal(syntax.unicode).map(Char)))
+        .or(ParserLeft.map(|left| fail(left, UnexpectedEnd)))
+}
+
+fn number() -> impl Parser<Item = usize> {
+    ParserChar
+        .filter(u8::is_ascii_digit)
+        .collect()
+        .filter_map(|v| std::str::from_utf8(&v).ok()?.parse().ok())
+}
+
+// `{` starts a name rather than a count
+fn name_start(x: &u8) -> bool {
+    x.is_ascii_alphabetic() || *x == b'_'
+}
+
+// {m}, {m,} or {m,n} with m <= n
+fn counted() -> impl Parser<Item = Parsed<Quantifier>> {
+    let count = satisfy(b'{')
+        .then(number().zip_with(
+            optional(satisfy(b',').then(optional(number()))),
+            |m, n| (m, n.unwrap_or(Some(m))),
+        ))
+        .skip(satisfy(b'}'));
+    let start = satisfy(b'{')
+        .then(optional(ParserChar.filter(name_start)))
+        .filter(Option::is_none);
+    ParserLeft.skip(lookahead(start)).zip_with(
+        optional(count),
+        |left, count| match count {
+            Some((m, n)) if n.is_none_or(|n| m <= n) => {
+                match m.max(n.unwrap_or(0)) > MAX_COUNT {
+                    true => fail(left, CountTooLarge),
+                    false => Ok(Quantifier::Counted(m, n)),
+                }
+            }
+            _ => fail(left, InvalidCount),
+        },
+    )
+}
+
+fn name() -> impl Parser<Item = Parsed<AST>> {
+    let name = satisfy(b'{')
+        .then(
+            ParserChar
+                .filter(|x| x.is_ascii_alphanumeric() || *x == b'_')
+                .collect(),
         )
+        .skip(satisfy(b'}'));
+    let start = satisfy(b'{').then(ParserChar.filter(name_start));
+    ParserLeft.skip(lookahead(start)).zip_with(
+        optional(name),
+        |left, v| match v {
+            // Offsets are only known once the whole pattern is parsed
+            Some(v) => Ok(Name(String::from_utf8(v).unwrap(), left)),
+            None => fail(left, InvalidName),
+        },
+    )
+}
+
+fn quantifier() -> impl Parser<Item = Parsed<Quantifier>> {
+    ok(ParserChar.filter_map(|x| match x {
+        b'*' => Some(Quantifier::Star),
+        b'+' => Some(Quantifier::Plus),
+        b'?' => Some(Quantifier::QnMk),
+        _ => None,
+    }))
+    .or(counted())
+}
+
+// [:name:] inside a class
+fn posix() -> impl Parser<Item = Parsed<CharacterClassItem>> {
+    let name = satisfy(b'[')
+        .then(satisfy(b':'))
+        .then(ParserChar.filter(|x| *x != b':').collect())
+        .skip(satisfy(b':'))
+        .skip(satisfy(b']'))
+        .filter_map(|v| PosixClass::from_name(std::str::from_utf8(&v).ok()?));
+    let start = satisfy(b'[').then(satisfy(b':'));
+    ParserLeft.skip(lookahead(start)).zip_with(
+        optional(name),
+        |left, p| match p {
+            Some(p) => Ok(CharacterClassItem::Posix(p)),
+            None => fail(left, UnknownPosixClass),
+        },
+    )
+}
+
+// The `-` of a range and the character ending it
+fn range_end(syntax: Syntax) -> impl Parser<Item = Parsed<(usize, u32)>> {
+    let end = lookahead(ParserChar.filter(|x| *x != b']')).then(atom(syntax));
+    ok(ParserLeft.skip(satisfy(b'-'))).try_zip_with(
+        optional(end).map(Option::transpose),
+        |left, hi| match hi {
+            Some(CharacterClassItem::Char(hi)) => Ok((left, hi)),
+            _ => fail(left, DanglingRange),
+        },
+    )
+}
+
+fn class_item(
+    syntax: Syntax,
+) -> impl Parser<Item = Parsed<CharacterClassItem>> {
+    use CharacterClassItem::*;
+    let item = ok(ParserLeft)
+        .try_zip_with(posix().or(atom(syntax)), |left, lo| Ok((left, lo)))
+        .try_zip_with(
+            optional(range_end(syntax)).map(Option::transpose),
+            |(left, lo), hi| match (lo, hi) {
+                (lo, None) => Ok(lo),
+                (Char(lo), Some((_, hi))) if lo <= hi => Ok(Range(lo..=hi)),
+                (Char(_), Some(_)) => fail(left, InvalidRange),
+                (_, Some((d, _))) => fail(d, DanglingRange),
+            },
+        );
+    let dangling = ParserLeft
+        .skip(satisfy(b'-'))
+        .map(|left| fail(left, DanglingRange));
+    lookahead(ParserChar.filter(|x| *x != b']')).then(dangling.or(item))
+}
+
+fn ccls(syntax: Syntax) -> impl Parser<Item = Parsed<AST>> {
+    let open = satisfy(b'[').then(optional(satisfy(b'^')));
+    ok(ParserLeft.zip_with(open, |left, x| (left, x.is_some())))
+        .try_zip_with(class_item(syntax).try_collect(), |x, v| Ok((x, v)))
+        .try_zip_with(
+            ok(optional(satisfy(b']'))),
+            move |((left, negated), v), close| match close {
+                None => fail(left, UnclosedClass),
+                Some(_) if v.is_empty() => fail(left, EmptyClass),
+                Some(_) => Ok(WithCharacterClass(CharacterClass::new(
+                    negated,
+                    syntax.unicode,
+                    v,
+                ))),
+            },
+        )
+}
+
+fn group(me: Recursive) -> impl Parser<Item = Parsed<AST>> {
+    // `(?:...)` groups without capturing
+    let open = satisfy(b'(').then(optional(satisfy(b'?').then(satisfy(b':'))));
+    let body = lookahead(ParserChar).then(me);
+    ok(ParserLeft.zip_with(open, |left, x| (left, x.is_none())))
+        .try_zip_with(optional(body).map(Option::transpose), |x, ast| {
+            Ok((x, ast))
+        })
+        .try_zip_with(
+            ok(optional(satisfy(b')'))),
+            |((left, capture), ast), close| match (ast, close) {
+                // Numbered once the whole pattern is parsed
+                (Some(ast), Some(_)) if capture => Ok(Group(0, Box::new(ast))),
+                (Some(ast), Some(_)) => Ok(ast),
+                _ => fail(left, UnclosedGroup),
+            },
+        )
+}
+
+fn primary(me: Recursive, syntax: Syntax) -> impl Parser<Item = Parsed<AST>> {
+    let repeat = ParserChar.filter(|x| matches!(x, b'*' | b'+' | b'?' | b'{'));
+    group(me)
+        .or(ccls(syntax))
+        .or(name())
+        .or(ParserLeft
+            .skip(lookahead(repeat))
+            .map(|left| fail(left, NothingToRepeat)))
+        .or(atom(syntax).map(move |x| {
+            x.map(|x| {
+                WithCharacterClass(CharacterClass::new(
+                    false,
+                    syntax.unicode,
+                    vec![x],
+                ))
+            })
+        }))
+}
+
+fn postfix(me: Recursive, syntax: Syntax) -> impl Parser<Item = Parsed<AST>> {
+    let again = ParserLeft.skip(lookahead(quantifier()));
+    primary(me, syntax)
+        .try_zip_with(
+            optional(quantifier()).map(Option::transpose),
+            |ast, q| {
+                Ok(match q {
+                    Some(q) => q.apply(ast),
+                    None => ast,
+                })
+            },
+        )
+        // `a**` or `a+?` would repeat a repetition: group it instead
+        .try_zip_with(ok(optional(again)), |ast, again| match again {
+            Some(left) => fail(left, RepeatedQuantifier),
+            None => Ok(ast),
+        })
+}
+
+// `~` binds looser than quantifiers: ~a* is ~(a*). The complement is
+// taken over bytes, so in Unicode mode it is cut down to valid UTF-8.
+fn unary(me: Recursive, syntax: Syntax) -> impl Parser<Item = Parsed<AST>> {
+    let not = satisfy(b'~').filter(move |_| syntax.boolean);
+    let complement = move |ast| {
+        let ast = Complement(Box::new(ast));
+        let any = CharacterClass::new(true, true, vec![]);
+        match syntax.unicode {
+            true => {
+                Intersection(vec![ast, Star(Box::new(WithCharacterClass(any)))])
+            }
+            false => ast,
+        }
+    };
+    ok(not.collect()).try_zip_with(postfix(me, syntax), move |v, ast| {
+        Ok(v.iter().fold(ast, |ast, _| complement(ast)))
     })
 }
 
+fn concatenation(
+    me: Recursive,
+    syntax: Syntax,
+) -> impl Parser<Item = Parsed<AST>> {
+    let more = lookahead(ParserChar.filter(move |x| match x {
+        b'|' | b')' => false,
+        b'&' => !syntax.boolean,
+        _ => true,
+    }));
+    ok(ParserLeft).try_zip_with(
+        more.then(unary(me, syntax)).try_collect(),
+        |left, mut v| match v.len() {
+            0 => fail(left, EmptyAlternative),
+            1 => Ok(v.pop().unwrap()),
+            _ => Ok(Concatenation(v)),
+        },
+    )
+}
+
+// One or more `p` separated by `sep`
+fn separated(
+    p: impl Parser<Item = Parsed<AST>>,
+    sep: u8,
+    f: fn(Vec<AST>) -> AST,
+) -> impl Parser<Item = Parsed<AST>> {
+    let p = Rc::new(p);
+    Rc::clone(&p).try_zip_with(
+        satisfy(sep).then(p).try_collect(),
+        move |x, mut v| {
+            v.insert(0, x);
+            Ok(match v.len() {
+                1 => v.pop().unwrap(),
+                _ => f(v),
+            })
+        },
+    )
+}
+
+// `&` binds looser than concatenation and tighter than `|`
+fn regex(syntax: Syntax) -> Rc<Box<dyn Parser<Item = Parsed<AST>>>> {
+    Rc::new_cyclic(|me: &Recursive| {
+        let intersection =
+            separated(concatenation(me.clone(), syntax), b'&', Intersection);
+        Box::new(separated(intersection, b'|', Alternation))
+    })
+}
+
+// Groups are numbered in the order of their `(` and names get their
+// offsets from the end of the pattern
+fn finish(ast: AST, len: usize, groups: &mut usize) -> AST {
+    let all = |v: Vec<AST>, groups: &mut usize| {
+        v.into_iter().map(|x| finish(x, len, groups)).collect()
+    };
+    let one =
+        |a: Box<AST>, groups: &mut usize| Box::new(finish(*a, len, groups));
+    match ast {
+        Concatenation(v) => Concatenation(all(v, groups)),
+        Alternation(v) => Alternation(all(v, groups)),
+        Intersection(v) => Intersection(all(v, groups)),
+        Star(a) => Star(one(a, groups)),
+        Plus(a) => Plus(one(a, groups)),
+        QnMk(a) => QnMk(one(a, groups)),
+        Counted(a, m, n) => Counted(one(a, groups), m, n),
+        Complement(a) => Complement(one(a, groups)),
+        Group(_, a) => {
+            *groups += 1;
+            let i = *groups;
+            Group(i, one(a, groups))
+        }
+        Name(x, left) => Name(x, len - left),
+        ast @ WithCharacterClass(_) => ast,
+    }
+}
+
+// The pattern's AST, or the offset and kind of its first error
+pub(crate) fn ast_regex(
+    s: &str,
+    syntax: Syntax,
+) -> Result<AST, (usize, RegexErrorKind)> {
+    let (ast, t) = regex(syntax)
+        .run(s.as_bytes())
+        .expect("an alternation always yields an item");
+    match ast {
+        Ok(ast) if t.is_empty() => Ok(finish(ast, s.len(), &mut 0)),
+        // The alternation only stops early at an unmatched `)`
+        Ok(_) => Err((s.len() - t.len(), UnopenedGroup)),
+        Err(e) => Err((s.len() - e.left, e.kind)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const BOOLEAN: Syntax = Syntax {
+        unicode: false,
+        boolean: true,
+    };
+
+    fn accept<T>(pattern: &str, p: impl Parser<Item = Parsed<T>>) -> bool {
+        matches!(p.run(pattern.as_bytes()), Some((Ok(_), [])))
+    }
+
+    fn error(pattern: &str) -> (usize, RegexErrorKind) {
+        ast_regex(pattern, BOOLEAN).unwrap_err()
+    }
+
     #[test]
     fn pat_meta() {
-        let pattern = r".".as_bytes();
-        assert!(meta().accept(pattern));
+        assert!(accept(r".", atom(BOOLEAN)));
+        assert!(accept(r"\d", atom(BOOLEAN)));
     }
 
     #[test]
     fn pat_atom() {
-        let pattern = r"a".as_bytes();
-        assert!(atom().accept(pattern));
+        assert!(accept(r"a", atom(BOOLEAN)));
     }
 
     #[test]
     fn pat_ccls() {
-        let pattern = r"[a\[\d\]c]".as_bytes();
-        assert!(ast_ccls().accept(pattern));
+        assert!(accept(r"[a\[\d\]c]", ccls(BOOLEAN)));
     }
 
     #[test]
     fn pat_ccls_range() {
-        let pattern = r"[eb-da]".as_bytes();
-        assert!(ast_ccls().accept(pattern));
+        assert!(accept(r"[eb-da]", ccls(BOOLEAN)));
     }
 
     #[test]
     fn pat_ccls_range_neg() {
-        let pattern = r"[^b-d\d]".as_bytes();
-        assert!(ast_ccls().accept(pattern));
+        assert!(accept(r"[^b-d\d]", ccls(BOOLEAN)));
+    }
+
+    #[test]
+    fn pat_escape() {
+        use CharacterClassItem::*;
+        let item = |s: &str| match atom(BOOLEAN).run(s.as_bytes()) {
+            Some((Ok(x), [])) => Some(x),
+            _ => None,
+        };
+        assert_eq!(item(r"\n"), Some(Char(0x0A)));
+        assert_eq!(item(r"\t"), Some(Char(0x09)));
+        assert_eq!(item(r"\x7f"), Some(Char(0x7F)));
+        assert_eq!(item(r"\x{41}"), Some(Char(0x41)));
+        assert_eq!(item(r"\0"), Some(Char(0)));
+        assert_eq!(item(r"\101"), Some(Char(0x41)));
+        assert_eq!(item(r"\377"), Some(Char(0xFF)));
+        assert_eq!(item(r"\D"), Some(NegatedMeta(MetaCharacter::D)));
+        assert_eq!(item(r"\S"), Some(NegatedMeta(MetaCharacter::S)));
+        assert_eq!(item(r"\W"), Some(NegatedMeta(MetaCharacter::W)));
+        assert_eq!(item(r"\1234"), None);
+        assert_eq!(item(r"\$"), item(r"$"));
+    }
+
+    #[test]
+    fn pat_ccls_posix() {
+        assert!(accept(r"[[:alpha:]_]", ccls(BOOLEAN)));
+        assert!(accept(r"[^[:space:][:punct:]]", ccls(BOOLEAN)));
+        assert!(accept(r"[\x00-\x1f[:xdigit:]]", ccls(BOOLEAN)));
     }
 
     #[test]
     fn pat_regex_single_char() {
-        let pattern = r"a".as_bytes();
-        assert!(ast_regex().accept(pattern));
+        assert!(ast_regex(r"a", BOOLEAN).is_ok());
     }
 
     #[test]
     fn pat_regex_qnmk() {
-        let pattern = r"(a)?".as_bytes();
-        assert!(ast_regex().accept(pattern));
+        assert!(ast_regex(r"(a)?", BOOLEAN).is_ok());
+    }
+
+    #[test]
+    fn pat_counted() {
+        assert!(accept(r"{3}", counted()));
+        assert!(accept(r"{3,}", counted()));
+        assert!(accept(r"{1,3}", counted()));
+        assert!(accept(r"{2,2}", counted()));
+        assert!(!accept(r"{3,1}", counted()));
+        assert!(!accept(r"{,3}", counted()));
+        assert!(!accept(r"{}", counted()));
+    }
+
+    #[test]
+    fn pat_regex_counted() {
+        assert!(ast_regex(r"\\([0-7]){1,3}", BOOLEAN).is_ok());
     }
 
     #[test]
     fn pat_regex_no_bracket() {
-        let pattern = r"\w?".as_bytes();
-        assert!(!ast_regex().accept(pattern));
+        assert!(ast_regex(r"\w?", BOOLEAN).is_ok());
+    }
+
+    #[test]
+    fn pat_regex_postfix() {
+        let ast = |s: &str| ast_regex(s, BOOLEAN).ok();
+        assert_eq!(ast(r"ab*"), ast(r"a(?:b)*"));
+        assert_eq!(ast(r"[ab]+c"), ast(r"(?:[ab])+c"));
+        assert_eq!(ast(r"a|b?"), ast(r"a|(?:b)?"));
+        assert_eq!(ast(r"(?:ab)"), ast(r"ab"));
+        assert!(ast(r"(a|b)c{2}").is_some());
+        assert!(ast(r"a+?").is_none());
+        assert!(ast(r"*a").is_none());
+        assert!(ast(r"a|*").is_none());
+    }
+
+    #[test]
+    fn pat_regex_boolean() {
+        let ast = |s: &str| ast_regex(s, BOOLEAN).ok();
+        assert_eq!(ast(r"a|b&c"), ast(r"a|(?:b&c)"));
+        assert_eq!(ast(r"ab&cd"), ast(r"(?:ab)&(?:cd)"));
+        assert_eq!(ast(r"~a*b"), ast(r"(?:~(?:a*))b"));
+        assert!(ast(r"~~a").is_some());
+        assert!(ast(r"\&\~").is_some());
+        assert!(ast(r"a&b|c&d").is_some());
+        let literal = |s: &str| ast_regex(s, Syntax::default()).ok();
+        assert_eq!(literal(r"a&~b"), literal(r"a\&\~b"));
+        assert!(literal(r"&").is_some() && ast(r"&").is_none());
+    }
+
+    #[test]
+    fn pat_regex_group() {
+        let ast = |s: &str| ast_regex(s, BOOLEAN).ok();
+        let lit = |x: u8| {
+            WithCharacterClass(CharacterClass::new(
+                false,
+                false,
+                vec![CharacterClassItem::Char(x as u32)],
+            ))
+        };
+        let group = |i, x| Group(i, Box::new(x));
+        assert_eq!(
+            ast(r"(a)(?:b)"),
+            Some(Concatenation(vec![group(1, lit(b'a')), lit(b'b')]))
+        );
+        assert_eq!(
+            ast(r"((a)|(?:b)(c))*"),
+            Some(Star(Box::new(group(
+                1,
+                Alternation(vec![
+                    group(2, lit(b'a')),
+                    Concatenation(vec![lit(b'b'), group(3, lit(b'c'))]),
+                ])
+            ))))
+        );
+        assert!(ast(r"(?:").is_none());
+    }
+
+    #[test]
+    fn pat_regex_name() {
+        let ast = |s: &str| ast_regex(s, BOOLEAN).ok();
+        let name = |x: &str, i| Name(x.to_owned(), i);
+        assert_eq!(ast(r"{D}"), Some(name("D", 0)));
+        assert_eq!(
+            ast(r"x{D_1}+"),
+            Some(Concatenation(vec![
+                WithCharacterClass(CharacterClass::new(
+                    false,
+                    false,
+                    vec![CharacterClassItem::Char(b'x' as u32)]
+                )),
+                Plus(Box::new(name("D_1", 1))),
+            ]))
+        );
+        assert_eq!(
+            ast(r"{D}{2}{E}"),
+            Some(Concatenation(vec![
+                Counted(Box::new(name("D", 0)), 2, Some(2)),
+                name("E", 6),
+            ]))
+        );
     }
 
     #[test]
     fn pat_regex_altr_conc() {
-        let pattern = r"a|bc".as_bytes();
-        assert!(ast_regex().accept(pattern));
+        assert!(ast_regex(r"a|bc", BOOLEAN).is_ok());
     }
 
     #[test]
     fn pat_1() {
-        let pattern = r"a|(b)?".as_bytes();
-        assert!(ast_regex().accept(pattern));
+        assert!(ast_regex(r"a|(b)?", BOOLEAN).is_ok());
     }
 
     #[test]
     fn pat_2() {
-        let pattern = r"(b)?c".as_bytes();
-        assert!(ast_regex().accept(pattern));
+        assert!(ast_regex(r"(b)?c", BOOLEAN).is_ok());
     }
 
     #[test]
     fn pat_3() {
-        let pattern = r"a|bc".as_bytes();
-        assert!(ast_regex().accept(pattern));
+        assert!(ast_regex(r"a|bc", BOOLEAN).is_ok());
     }
 
     #[test]
     fn pat_4() {
-        let pattern = r"a|(b(cd)*)?e".as_bytes();
-        assert!(ast_regex().accept(pattern));
+        assert!(ast_regex(r"a|(b(cd)*)?e", BOOLEAN).is_ok());
     }
 
     #[test]
     fn pat_5() {
-        let pattern = r"(\-)?[1-9](\d)+".as_bytes();
-        assert!(ast_regex().accept(pattern));
+        assert!(ast_regex(r"(\-)?[1-9](\d)+", BOOLEAN).is_ok());
+    }
+
+    #[test]
+    fn pat_errors() {
+        assert_eq!(error(r""), (0, EmptyAlternative));
+        assert_eq!(error(r"a|"), (2, EmptyAlternative));
+        assert_eq!(error(r"a()"), (2, EmptyAlternative));
+        assert_eq!(error(r"ab(c|d"), (2, UnclosedGroup));
+        assert_eq!(error(r"ab("), (2, UnclosedGroup));
+        assert_eq!(error(r"ab)c"), (2, UnopenedGroup));
+        assert_eq!(error(r"x[abc"), (1, UnclosedClass));
+        assert_eq!(error(r"x[]"), (1, EmptyClass));
+        assert_eq!(error(r"x[^]"), (1, EmptyClass));
+        assert_eq!(error(r"[a-]"), (2, DanglingRange));
+        assert_eq!(error(r"[-a]"), (1, DanglingRange));
+        assert_eq!(error(r"[\d-a]"), (3, DanglingRange));
+        assert_eq!(error(r"[a-\d]"), (2, DanglingRange));
+        assert_eq!(error(r"[z-a]"), (1, InvalidRange));
+        assert_eq!(error(r"ab\q"), (2, InvalidEscape(b'q')));
+        assert_eq!(error(r"ab\"), (3, UnexpectedEnd));
+        assert_eq!(error(r"a-b"), (1, UnexpectedByte(b'-')));
+        assert_eq!(error(r"a|+"), (2, NothingToRepeat));
+        assert_eq!(error(r"a**"), (2, RepeatedQuantifier));
+        assert_eq!(error(r"a+?"), (2, RepeatedQuantifier));
+        assert_eq!(error(r"a{2}{3}"), (4, RepeatedQuantifier));
+        assert!(ast_regex(r"(a*)*", BOOLEAN).is_ok());
+        assert_eq!(error(r"a{3,1}"), (1, InvalidCount));
+        assert_eq!(error(r"a{1,2"), (1, InvalidCount));
+        assert_eq!(error(r"a{1001}"), (1, CountTooLarge));
+        assert_eq!(error(r"a{1,100000}"), (1, CountTooLarge));
+        assert_eq!(error(r"a{99999999999999999999}"), (1, InvalidCount));
+        assert_eq!(error(r"a{b"), (1, InvalidName));
+        assert_eq!(error(r"a{b-c}"), (1, InvalidName));
+        assert_eq!(error(r"a&"), (2, EmptyAlternative));
+        assert_eq!(error(r"&a"), (0, EmptyAlternative));
+        assert_eq!(error(r"a~"), (2, UnexpectedEnd));
+        assert_eq!(error(r"a\x4"), (1, InvalidHexEscape));
+        assert_eq!(error(r"a\x4g"), (1, InvalidHexEscape));
+        assert_eq!(error(r"a\x{}"), (1, InvalidHexEscape));
+        assert_eq!(error(r"a\x{41"), (1, InvalidHexEscape));
+        assert_eq!(error(r"a\x{100}"), (1, OutOfRange));
+        assert_eq!(error(r"a\400"), (1, OutOfRange));
+        assert_eq!(error(r"[[:foo:]]"), (1, UnknownPosixClass));
+        assert_eq!(error(r"[[:alpha]"), (1, UnknownPosixClass));
+        assert_eq!(error(r"[[:alpha:]-z]"), (10, DanglingRange));
+        let unicode = Syntax {
+            unicode: true,
+            ..BOOLEAN
+        };
+        let e = ast_regex(r"\x{D800}", unicode).unwrap_err();
+        assert_eq!(e, (0, OutOfRange));
     }
 }
@@ -0,0 +1,117 @@
+use std::ops::RangeInclusive;
+
+const SURROGATES: RangeInclusive<u32> = 0xD800..=0xDFFF;
+
+fn encode(x: u32) -> Vec<u8> {
+    let c = char::from_u32(x).expect("scalar value");
+    let mut buf = [0; 4];
+    c.encode_utf8(&mut buf).as_bytes().to_vec()
+}
+
+// Splits a range of codepoints into byte-range sequences that match exactly
+// the UTF-8 encodings of its scalar values, in the manner of utf8-ranges.
+pub(crate) fn utf8_sequences(
+    r: RangeInclusive<u32>,
+    out: &mut Vec<Vec<RangeInclusive<u8>>>,
+) {
+    let (lo, hi) = r.into_inner();
+    if lo > hi {
+        return;
+    }
+    if lo <= *SURROGATES.end() && hi >= *SURROGATES.start() {
+        if lo < *SURROGATES.start() {
+            utf8_sequences(lo..=*SURROGATES.start() - 1, out);
+        }
+        if hi > *SURROGATES.end() {
+            utf8_sequences(*SURROGATES.end() + 1..=hi, out);
+        }
+        return;
+    }
+    // Same encoded length on both ends
+    for max in [0x7F, 0x7FF, 0xFFFF] {
+        if lo <= max && max < hi {
+            utf8_sequences(lo..=max, out);
+            utf8_sequences(max + 1..=hi, out);
+            return;
+        }
+    }
+    // Same prefix on both ends, trailing bytes span their full range
+    for i in 1..4 {
+        let m = (1u32 << (6 * i)) - 1;
+        if lo & !m != hi & !m {
+            if lo & m != 0 {
+                utf8_sequences(lo..=lo | m, out);
+                utf8_sequences((lo | m) + 1..=hi, out);
+                return;
+            }
+            if hi & m != m {
+                utf8_sequences(lo..=(hi & !m) - 1, out);
+                utf8_sequences(hi & !m..=hi, out);
+                return;
+            }
+        }
+    }
+    out.push(
+        encode(lo)
+            .into_iter()
+            .zip(encode(hi))
+            .map(|(a, b)| a..=b)
+            .collect(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(seqs: &[Vec<RangeInclusive<u8>>], s: &[u8]) -> bool {
+        seqs.iter().any(|seq| {
+            seq.len() == s.len()
+                && seq.iter().zip(s).all(|(r, x)| r.contains(x))
+        })
+    }
+
+    #[test]
+    fn utf8_ascii() {
+        let mut v = Vec::new();
+        utf8_sequences(0x61..=0x7A, &mut v);
+        assert_eq!(v, vec![vec![b'a'..=b'z']]);
+    }
+
+    #[test]
+    fn utf8_greek() {
+        let mut v = Vec::new();
+        utf8_sequences('α' as u32..='ω' as u32, &mut v);
+        assert_eq!(
+            v,
+            vec![
+                vec![0xCE..=0xCE, 0xB1..=0xBF],
+                vec![0xCF..=0xCF, 0x80..=0x89]
+            ]
+        );
+    }
+
+    #[test]
+    fn utf8_exhaustive() {
+        let ranges = [
+            0..=char::MAX as u32,
+            0x7F..=0x800,
+            0xD000..=0xE100,
+            0x10000..=0x10FFFF,
+            0x3FF..=0x10401,
+        ];
+        for r in ranges {
+            let mut v = Vec::new();
+            utf8_sequences(r.clone(), &mut v);
+            for x in (0..=char::MAX as u32).step_by(7) {
+                if let Some(c) = char::from_u32(x) {
+                    let mut buf = [0; 4];
+                    let s = c.encode_utf8(&mut buf).as_bytes();
+                    assert_eq!(matches(&v, s), r.contains(&x), "{:x}", x);
+                }
+            }
+            // no sequence accepts the encoding of a surrogate
+            assert!(!matches(&v, &[0xED, 0xA0, 0x80]));
+        }
+    }
+}
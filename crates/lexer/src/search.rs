@@ -0,0 +1,430 @@
+use crate::{
+    automata::{dfa::DFA, Automaton, ParserAutomaton, IR},
+    char_len,
+    regex::Syntax,
+    ParseRegexError,
+};
+use std::ops::Range;
+
+/// A pattern compiled for searching, in the syntax of the lexer rules with
+/// the operators of [`LexerBuilder::boolean`]. Matches are
+/// leftmost-longest: of the matches starting first, the longest. A `^` at
+/// the start of a branch of the pattern, one side of a `|` outside any
+/// group, anchors the branch to the start of the haystack and a `$` at the
+/// end to the end. Elsewhere `^` is written `\^`, while `$` stands for
+/// itself; `\$` is also a `$`, and the way to write one ending a branch.
+/// Errors name the pattern as rule 0.
+///
+/// Patterns can also be compared as languages: the strings each matches
+/// in full, anchors aside. Each comparison has a counterpart giving a
+/// shortest string that shows it fails. [`LexerBuilder::rule_regex`]
+/// gives the pattern of a lexer rule, to compare rules the same way.
+///
+/// [`LexerBuilder::boolean`]: crate::LexerBuilder::boolean
+/// [`LexerBuilder::rule_regex`]: crate::LexerBuilder::rule_regex
+pub struct Regex {
+    // The branches, gathered by how they are anchored
+    parts: Vec<Part>,
+    // The whole pattern anchored at the start, anchors aside, for comparing
+    language: DFA,
+    unicode: bool,
+}
+
+// The branches of a pattern with the same anchors
+struct Part {
+    // Anchored at the start, for the end of a match
+    forward: ParserAutomaton<DFA>,
+    // Anywhere, for `is_match`
+    unanchored: DFA,
+    // Reversed, run backwards to find where matches start
+    reverse: DFA,
+    start: bool,
+    end: bool,
+}
+
+// Whether `dfa` accepts a prefix of `s`, or all of it if `whole`
+fn accepts(dfa: &DFA, s: &[u8], whole: bool) -> bool {
+    let mut q = dfa.initial_state();
+    for &x in s {
+        if !whole && dfa.category(&q).is_some() {
+            return true;
+        }
+        match dfa.transition(&q, x) {
+            Some(z) => q = z,
+            None => return false,
+        }
+    }
+    dfa.category(&q).is_some()
+}
+
+// The branches of `pattern` between its `|` outside groups and classes.
+// An unopened `)` ends the splitting, for the parser to report.
+fn branches(pattern: &str) -> Vec<Range<usize>> {
+    let s = pattern.as_bytes();
+    let (mut v, mut from, mut depth, mut i) = (vec![], 0, 0, 0);
+    while i < s.len() {
+        match s[i] {
+            b'\\' => i += 1,
+            b'[' => {
+                i += 1 + (s.get(i + 1) == Some(&b'^')) as usize;
+                // Up to the `]` closing the class, past `[:name:]`
+                while i < s.len() && s[i] != b']' {
+                    i += match (s[i], s.get(i + 1)) {
+                        (b'\\', _) => 2,
+                        (b'[', Some(b':')) => s[i..]
+                            .windows(2)
+                            .position(|w| w == b":]")
+                            .map_or(s.len() - i, |n| n + 2),
+                        _ => 1,
+                    };
+                }
+            }
+            b'(' => depth += 1,
+            b')' if depth == 0 => break,
+            b')' => depth -= 1,
+            b'|' if depth == 0 => {
+                v.push(from..i);
+                from = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    v.push(from..s.len());
+    v
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, ParseRegexError> {
+        Self::compile(pattern, false)
+    }
+
+    /// Reads the pattern as codepoints, as [`LexerBuilder::unicode`]
+    /// does, and steps over whole characters after an empty match.
+    ///
+    /// [`LexerBuilder::unicode`]: crate::LexerBuilder::unicode
+    pub fn new_unicode(pattern: &str) -> Result<Self, ParseRegexError> {
+        Self::compile(pattern, true)
+    }
+
+    fn compile(pattern: &str, unicode: bool) -> Result<Self, ParseRegexError> {
+        let syntax = Syntax {
+            unicode,
+            boolean: true,
+        };
+        let branches = branches(pattern);
+        let single = branches.len() == 1;
+        // The branches anchored each way, by `2 * start + end`
+        let mut anchored: [Vec<IR>; 4] = Default::default();
+        for r in branches {
+            let s = &pattern[r.clone()];
+            let start = s.starts_with('^');
+            let s = &s[start as usize..];
+            // A `$` after an odd run of backslashes is escaped
+            let (s, end) = match s.strip_suffix('$') {
+                Some(t)
+                    if (t.len() - t.trim_end_matches('\\').len()) % 2 == 0 =>
+                {
+                    (t, true)
+                }
+                _ => (s, false),
+            };
+            let ir = match s {
+                "" if single || start || end => IR::E,
+                s => {
+                    IR::from_regex(s, syntax).map_err(|e| ParseRegexError {
+                        offset: e.offset + r.start + start as usize,
+                        ..e
+                    })?
+                }
+            };
+            anchored[2 * start as usize + end as usize].push(ir);
+        }
+        let parts =
+            anchored.into_iter().enumerate().filter_map(|(i, mut v)| {
+                let ir = match v.len() {
+                    0 => return None,
+                    1 => v.pop().unwrap(),
+                    _ => IR::U(v),
+                };
+                Some((ir, i >= 2, i % 2 == 1))
+            });
+        Ok(Self::from_parts(parts.collect(), unicode))
+    }
+
+    // The pattern `ir`, unanchored
+    pub(crate) fn from_ir(ir: IR, unicode: bool) -> Self {
+        Self::from_parts(vec![(ir, false, false)], unicode)
+    }
+
+    // A pattern of the parts `(ir, start, end)`, anchored at the start if
+    // `start` and at the end if `end`
+    fn from_parts(parts: Vec<(IR, bool, bool)>, unicode: bool) -> Self {
+        let any = || IR::K(Box::new(IR::L((0..=255).collect())));
+        let language = match parts.as_slice() {
+            [_] => None,
+            v => Some(DFA::from_ir(&IR::U(
+                v.iter().map(|(ir, _, _)| ir.clone()).collect(),
+            ))),
+        };
+        let parts: Vec<_> = parts
+            .into_iter()
+            .map(|(ir, start, end)| {
+                let unanchored = IR::C(vec![any(), ir.clone()]);
+                // Matches that must end at the end all start from there
+                let reverse = match end {
+                    true => ir.reverse(),
+                    false => IR::C(vec![any(), ir.reverse()]),
+                };
+                Part {
+                    forward: ParserAutomaton(DFA::from_ir(&ir)),
+                    unanchored: DFA::from_ir(&unanchored),
+                    reverse: DFA::from_ir(&reverse),
+                    start,
+                    end,
+                }
+            })
+            .collect();
+        Self {
+            language: language.unwrap_or_else(|| parts[0].forward.0.clone()),
+            parts,
+            unicode,
+        }
+    }
+
+    /// Whether the pattern matches anywhere in `haystack`. Stops at the
+    /// first match found, without looking for where it starts.
+    pub fn is_match(&self, haystack: &[u8]) -> bool {
+        self.parts.iter().any(|p| match p.start {
+            true => accepts(&p.forward.0, haystack, p.end),
+            false => accepts(&p.unanchored, haystack, p.end),
+        })
+    }
+
+    /// The byte range of the leftmost-longest match.
+    pub fn find(&self, haystack: &[u8]) -> Option<Range<usize>> {
+        self.find_iter(haystack).next()
+    }
+
+    /// The successive matches that do not overlap, each the
+    /// leftmost-longest match after the last. An empty match right where
+    /// the last one ended is skipped.
+    pub fn find_iter<'a>(&'a self, haystack: &'a [u8]) -> Matches<'a> {
+        Matches {
+            regex: self,
+            starts: self.starts(haystack),
+            haystack,
+            i: 0,
+            last: None,
+        }
+    }
+
+    /// Whether both patterns match exactly the same strings.
+    pub fn is_equivalent(&self, other: &Regex) -> bool {
+        self.equivalence_counterexample(other).is_none()
+    }
+
+    /// Whether `other` matches every string this does.
+    pub fn is_subset(&self, other: &Regex) -> bool {
+        self.subset_counterexample(other).is_none()
+    }
+
+    /// Whether no string matches both patterns.
+    pub fn intersection_is_empty(&self, other: &Regex) -> bool {
+        self.intersection_witness(other).is_none()
+    }
+
+    /// A shortest string that only one of the patterns matches.
+    pub fn equivalence_counterexample(&self, other: &Regex) -> Option<Vec<u8>> {
+        self.language.witness(&other.language, |a, b| a != b)
+    }
+
+    /// A shortest string this matches and `other` does not.
+    pub fn subset_counterexample(&self, other: &Regex) -> Option<Vec<u8>> {
+        self.language.witness(&other.language, |a, b| a && !b)
+    }
+
+    /// A shortest string both patterns match.
+    pub fn intersection_witness(&self, other: &Regex) -> Option<Vec<u8>> {
+        self.language.witness(&other.language, |a, b| a && b)
+    }
+
+    // Whether a match starts at each offset, up to and including the end
+    fn starts(&self, s: &[u8]) -> Vec<bool> {
+        let mut v = vec![false; s.len() + 1];
+        for p in &self.parts {
+            for (x, y) in v.iter_mut().zip(p.starts(s)) {
+                *x |= y;
+            }
+        }
+        v
+    }
+}
+
+impl Part {
+    // Whether a match of these branches starts at each offset, from one
+    // backward pass over `s`
+    fn starts(&self, s: &[u8]) -> Vec<bool> {
+        let mut v = vec![false; s.len() + 1];
+        if self.start {
+            v[0] = accepts(&self.forward.0, s, self.end);
+            return v;
+        }
+        let mut q = self.reverse.initial_state();
+        v[s.len()] = self.reverse.category(&q).is_some();
+        for i in (0..s.len()).rev() {
+            match self.reverse.transition(&q, s[i]) {
+                Some(z) => q = z,
+                None => break,
+            }
+            v[i] = self.reverse.category(&q).is_some();
+        }
+        v
+    }
+
+    // The length of the longest match of these branches at `i`
+    fn longest(&self, s: &[u8], i: usize) -> Option<usize> {
+        if self.start && i > 0 {
+            return None;
+        }
+        // The longest match from a start reaches the end if any match
+        // from there does
+        let n = self.forward.scan(&s[i..]).0?.1;
+        (!self.end || i + n == s.len()).then_some(n)
+    }
+}
+
+/// The matches of [`Regex::find_iter`], as byte ranges.
+pub struct Matches<'a> {
+    regex: &'a Regex,
+    starts: Vec<bool>,
+    haystack: &'a [u8],
+    i: usize,
+    // End of the last match
+    last: Option<usize>,
+}
+
+impl<'a> Iterator for Matches<'a> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.haystack;
+        while self.i <= s.len() {
+            let i = self.i;
+            if self.starts[i] {
+                let parts = self.regex.parts.iter();
+                let n = parts.filter_map(|p| p.longest(s, i)).max();
+                let n = n.expect("a match starts here");
+                if n > 0 || self.last != Some(i) {
+                    self.i = i + n;
+                    self.last = Some(i + n);
+                    return Some(i..i + n);
+                }
+            }
+            self.i += match self.regex.unicode && i < s.len() {
+                true => char_len(&s[i..]),
+                false => 1,
+            };
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all(r: &str, s: &str) -> Vec<Range<usize>> {
+        Regex::new(r).unwrap().find_iter(s.as_bytes()).collect()
+    }
+
+    #[test]
+    fn search_leftmost_longest() {
+        // `bc` ends first, but `abcd` starts first
+        let r = Regex::new(r"abcd|bc").unwrap();
+        assert_eq!(r.find(b"xabcd"), Some(1..5));
+        assert_eq!(r.find(b"xabce"), Some(2..4));
+        assert_eq!(r.find(b"xabe"), None);
+        assert!(r.is_match(b"xabce") && !r.is_match(b"xabe"));
+        assert_eq!(all(r"\d+", "a1 22 333b"), vec![1..2, 3..5, 6..9]);
+        assert_eq!(all(r"a|ab|abc", "abcab"), vec![0..3, 3..5]);
+        assert_eq!(all(r"\w+&~\d+", "12 a1 3"), vec![3..5]);
+    }
+
+    #[test]
+    fn search_empty() {
+        assert_eq!(all(r"a*", "baa"), vec![0..0, 1..3]);
+        assert_eq!(all(r"a*", ""), vec![0..0]);
+        assert_eq!(all(r"x?", "ab"), vec![0..0, 1..1, 2..2]);
+        let r = Regex::new_unicode(r"x?").unwrap();
+        let v: Vec<_> = r.find_iter("é!".as_bytes()).collect();
+        assert_eq!(v, vec![0..0, 2..2, 3..3]);
+    }
+
+    #[test]
+    fn search_anchors() {
+        assert_eq!(all(r"^a+", "aab aa"), vec![0..2]);
+        assert_eq!(all(r"^a+", "baa"), vec![]);
+        assert_eq!(all(r"a+$", "aab aa"), vec![4..6]);
+        assert_eq!(all(r"^a+$", "aaa"), vec![0..3]);
+        assert_eq!(all(r"^a+$", "aaab"), vec![]);
+        assert_eq!(all(r"^$", ""), vec![0..0]);
+        assert_eq!(all(r"b\$", "b$b"), vec![0..2]);
+        assert_eq!(all(r"a\^b$c", "a^b$c"), vec![0..5]);
+        assert_eq!(all(r"\\$", "a\\"), vec![1..2]);
+        let r = Regex::new(r"a+$").unwrap();
+        assert!(r.is_match(b"baa") && !r.is_match(b"aab"));
+        let r = Regex::new(r"^a").unwrap();
+        assert!(r.is_match(b"ab") && !r.is_match(b"ba"));
+        let e = Regex::new(r"^a(").err().unwrap();
+        assert_eq!(e.offset, 2);
+        assert_eq!(all(r"a\$b", "a$b"), vec![0..3]);
+        assert_eq!(all(r"\^\$", "^$"), vec![0..2]);
+    }
+
+    #[test]
+    fn search_anchored_branches() {
+        let r = Regex::new(r"a|b$").unwrap();
+        assert_eq!(r.find(b"ax"), Some(0..1));
+        assert_eq!(r.find(b"xbb"), Some(2..3));
+        assert!(!r.is_match(b"bx"));
+        assert_eq!(all(r"^a|a$|b", "abaa"), vec![0..1, 1..2, 3..4]);
+        assert_eq!(all(r"^ab|a+$", "abaa"), vec![0..2, 2..4]);
+        // Inside a group, `|` splits no branches
+        assert_eq!(all(r"(a|b$)", "b$a"), vec![0..2, 2..3]);
+        assert_eq!(all(r"[\|[:digit:]]$|^x", "x|"), vec![0..1, 1..2]);
+        assert_eq!(all(r"^|x", "ax"), vec![0..0, 1..2]);
+        let e = Regex::new(r"^a|").err().unwrap();
+        assert_eq!(e.offset, 3);
+        let e = Regex::new(r"a|^(b").err().unwrap();
+        assert_eq!(e.offset, 3);
+        let re = |r| Regex::new(r).unwrap();
+        assert!(re(r"^a|b$").is_equivalent(&re(r"[ab]")));
+    }
+
+    #[test]
+    fn search_languages() {
+        let re = |r| Regex::new(r).unwrap();
+        // A rewritten rule for C block comments
+        let old = re(r"/\*~([\s\S]*\*/[\s\S]*)\*/");
+        let new = re(r"/\*([^\*]|\*+[^\*/])*\*+/");
+        assert!(old.is_equivalent(&new));
+        assert_eq!(old.equivalence_counterexample(&new), None);
+        let wrong = re(r"/\*([^\*]|\*[^\*/])*\*/");
+        assert!(!old.is_equivalent(&wrong));
+        let e = old.equivalence_counterexample(&wrong);
+        assert_eq!(e, Some(b"/***/".to_vec()));
+        assert!(wrong.is_subset(&old) && !old.is_subset(&wrong));
+        assert_eq!(wrong.subset_counterexample(&old), None);
+        let e = old.subset_counterexample(&wrong);
+        assert_eq!(e, Some(b"/***/".to_vec()));
+        let ident = re(r"\l\w*");
+        assert!(ident.intersection_is_empty(&re(r"\d+")));
+        let keyword = re(r"if|else");
+        assert!(!ident.intersection_is_empty(&keyword));
+        let e = ident.intersection_witness(&keyword);
+        assert_eq!(e, Some(b"if".to_vec()));
+        // Anchors only matter to searching
+        assert!(re(r"^a+$").is_equivalent(&re(r"aa*")));
+    }
+}
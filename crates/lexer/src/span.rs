@@ -0,0 +1,86 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    ops::Range,
+    str::Utf8Error,
+};
+
+/// A place in the source, both counted from 1. Columns count characters
+/// of UTF-8, so a multibyte character takes one column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The text of a token, borrowed from the input instead of copied, and
+/// its byte range in the input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lexeme<'a> {
+    pub text: &'a [u8],
+    pub span: Range<usize>,
+}
+
+impl<'a> Lexeme<'a> {
+    /// The text as a `str`, as long as the input the lexeme was cut from.
+    pub fn as_str(&self) -> Result<&'a str, Utf8Error> {
+        std::str::from_utf8(self.text)
+    }
+}
+
+/// Maps byte offsets into `s` to positions, finding the line by binary
+/// search over the offsets where lines start.
+pub struct LineIndex<'a> {
+    s: &'a [u8],
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(s: &'a [u8]) -> Self {
+        let v = s.iter().enumerate().filter(|&(_, &x)| x == b'\n');
+        let starts = std::iter::once(0).chain(v.map(|(i, _)| i + 1)).collect();
+        Self { s, starts }
+    }
+
+    /// Panics if `offset` is past the end of the source; the end itself
+    /// is a position.
+    pub fn position(&self, offset: usize) -> Position {
+        assert!(offset <= self.s.len(), "offset {} out of range", offset);
+        let line = self.starts.partition_point(|&i| i <= offset) - 1;
+        let s = &self.s[self.starts[line]..offset];
+        // Continuation bytes do not start a character
+        let column = s.iter().filter(|&&x| x & 0xC0 != 0x80).count();
+        Position {
+            line: line + 1,
+            column: column + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn span_position() {
+        let s = "ab\n\ncafé x\n";
+        let index = LineIndex::new(s.as_bytes());
+        let at = |i| {
+            let Position { line, column } = index.position(i);
+            (line, column)
+        };
+        assert_eq!(at(0), (1, 1));
+        assert_eq!(at(2), (1, 3));
+        assert_eq!(at(3), (2, 1));
+        assert_eq!(at(4), (3, 1));
+        // `é` is two bytes but one column
+        assert_eq!(at(s.find('x').unwrap()), (3, 6));
+        assert_eq!(at(s.len()), (4, 1));
+        assert_eq!(index.position(1).to_string(), "1:2");
+    }
+}
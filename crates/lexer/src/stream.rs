@@ -0,0 +1,211 @@
+use crate::{Context, LexError, Lexer, Modes, Progress, Step, Token};
+use std::{
+    io::{self, ErrorKind, Read},
+    ops::Range,
+};
+
+const CHUNK: usize = 8192;
+
+/// Lexes input read a chunk at a time, like [`Lexer::tokens`] on all of
+/// it. The buffer is refilled whenever the automaton is still running at
+/// its end, so a token can span any number of reads; consumed input is
+/// dropped as the buffer is refilled. Spans are byte offsets from the start
+/// of the input.
+pub struct Stream<'a, R, T: Token, E, Ctx = ()> {
+    lexer: &'a Lexer<T, E, Ctx>,
+    ctx: Context<'a, Ctx>,
+    modes: Modes,
+    // How far the token at `i` was scanned before the buffer ran out
+    progress: Progress,
+    reader: R,
+    // Read into up to `n`, the rest is room for the next read
+    buf: Vec<u8>,
+    n: usize,
+    // Consumed bytes at the front of `buf`
+    i: usize,
+    // Offset in the input of `buf[0]`
+    offset: usize,
+    end: bool,
+}
+
+impl<'a, R: Read, T: Token, E, Ctx> Stream<'a, R, T, E, Ctx> {
+    pub(crate) fn new(
+        lexer: &'a Lexer<T, E, Ctx>,
+        ctx: Context<'a, Ctx>,
+        reader: R,
+    ) -> Self {
+        Self {
+            lexer,
+            ctx,
+            modes: Modes::new(),
+            progress: Progress::default(),
+            reader,
+            buf: Vec::new(),
+            n: 0,
+            i: 0,
+            offset: 0,
+            end: false,
+        }
+    }
+
+    /// The name of the mode the next token is lexed in.
+    pub fn mode(&self) -> &'a str {
+        self.lexer.mode_name(&self.modes)
+    }
+
+    // The buffer only grows, doubling, when unconsumed input fills it, and
+    // the scan goes on from where it ran out, so a long token costs linear
+    // time however few bytes each read gives
+    fn refill(&mut self) -> io::Result<()> {
+        // Only after a token, so a long one is not moved on every read
+        if self.i > 0 {
+            self.buf.copy_within(self.i..self.n, 0);
+            self.offset += self.i;
+            self.n -= self.i;
+            self.i = 0;
+        }
+        if self.n == self.buf.len() {
+            self.buf.resize(CHUNK.max(2 * self.n), 0);
+        }
+        let k = loop {
+            match self.reader.read(&mut self.buf[self.n..]) {
+                Ok(k) => break k,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        self.n += k;
+        self.end = k == 0;
+        Ok(())
+    }
+}
+
+impl<'a, R: Read, T: Token + Clone, E, Ctx> Iterator
+    for Stream<'a, R, T, E, Ctx>
+{
+    type Item = io::Result<(Result<T, LexError<E>>, Range<usize>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let s = &self.buf[self.i..self.n];
+            let mode = self.modes.current();
+            let progress = &mut self.progress;
+            let (r, n) = match self.lexer.step(mode, s, self.end, progress)? {
+                Step::Token(rule, n) => {
+                    let ctx = self.ctx.get();
+                    let r =
+                        self.lexer.apply(ctx, &mut self.modes, rule, &s[..n]);
+                    match r {
+                        Ok(r) => (r.map_err(LexError::Action), n),
+                        // The buffer does not live as long as the input
+                        Err(_) => (Err(LexError::Borrow), n),
+                    }
+                }
+                Step::Unmatched(n) => (Err(LexError::Unmatched), n),
+                Step::More => match self.refill() {
+                    Ok(()) => continue,
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            let start = self.offset + self.i;
+            self.i += n;
+            self.progress = Progress::default();
+            return Some(Ok((r, start..start + n)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Action, LexError, LexerBuilder};
+    use std::io::{self, Read};
+
+    // Hands out at most `n` bytes per read
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.1.min(buf.len()).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn stream_same_as_tokens() {
+        let lexer = LexerBuilder::<usize, ()>::new()
+            .rule(r"\w+", Action::C(0))
+            .rule(r"\s+", Action::C(1))
+            .rule(r"/\*~([\s\S]*\*/[\s\S]*)\*/", Action::C(2))
+            .rule(r"/", Action::C(3))
+            .build()
+            .unwrap();
+        let s = "abc /* x */ de\u{e9}f// g@ /* unclosed".repeat(3);
+        let expected: Vec<_> = lexer.tokens(s.as_bytes()).collect();
+        assert!(expected.contains(&(Err(LexError::Unmatched), 14..16)));
+        assert!(expected.contains(&(Err(LexError::Unmatched), 21..22)));
+        for n in [1, 2, 3, 7, 100] {
+            let v: Vec<_> = lexer
+                .stream(Trickle(s.as_bytes(), n))
+                .collect::<io::Result<_>>()
+                .unwrap();
+            assert_eq!(v, expected, "{}", n);
+        }
+    }
+
+    #[test]
+    fn stream_long_token() {
+        let lexer = LexerBuilder::<usize, ()>::new()
+            .rule(r"a+", Action::C(0))
+            .rule(r"b", Action::C(1))
+            .build()
+            .unwrap();
+        let s = "a".repeat(100_000) + "b";
+        let mut stream = lexer.stream(Trickle(s.as_bytes(), 1000));
+        assert_eq!(stream.next().unwrap().unwrap(), (Ok(0), 0..100_000));
+        assert_eq!(stream.next().unwrap().unwrap(), (Ok(1), 100_000..100_001));
+        assert!(stream.next().is_none());
+        // Only grown when full, by doubling
+        assert_eq!(stream.buf.len(), 131_072);
+    }
+
+    #[test]
+    fn stream_long_token_bytewise() {
+        let build = |b: LexerBuilder<'static, usize, ()>| {
+            b.rule(r"[ab]*b", Action::C(0))
+                .rule(r"[ab]", Action::C(1))
+                .build()
+                .unwrap()
+        };
+        // The scan goes on over each byte as it comes in, instead of
+        // starting over from the start of the token
+        let lexers = [
+            (build(LexerBuilder::new()), 4 << 20),
+            (build(LexerBuilder::new().lazy(4)), 1 << 18),
+        ];
+        for (lexer, n) in lexers {
+            let s = "ab".repeat(n / 2) + "a";
+            let mut stream = lexer.stream(Trickle(s.as_bytes(), 1));
+            assert_eq!(stream.next().unwrap().unwrap(), (Ok(0), 0..n));
+            assert_eq!(stream.next().unwrap().unwrap(), (Ok(1), n..n + 1));
+            assert!(stream.next().is_none());
+        }
+    }
+
+    #[test]
+    fn stream_error() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+        let lexer = LexerBuilder::<(), ()>::new()
+            .rule(r"a", Action::C(()))
+            .build()
+            .unwrap();
+        let e = lexer.stream(Broken).next().unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "broken");
+    }
+}